@@ -0,0 +1,121 @@
+// Command line for demos. The plan lives in a JSON file (--state, plan.json by
+// default) between invocations:
+//
+//   prop compute --riders 2 --orders 5
+//   prop event reject --rider 1 --order 3
+//   prop show
+//
+// Every command prints the resulting plan.
+
+use crate::{
+    compute_plan, load_plan, process_event, save_plan, Event, Order, Plan, PlanDisplay, Rider,
+};
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage: prop [--state FILE] <command>
+commands:
+  compute --riders N --orders M   round-robin M orders over N riders
+  event reject --rider R --order O
+  show";
+
+// Runs the command in `args` (without the program name) and returns the exit
+// code: 0 on success, 1 when the command failed, 2 on bad usage
+pub fn run(mut args: Vec<String>) -> i32 {
+    let state = match take_flag(&mut args, "--state") {
+        Ok(path) => PathBuf::from(path.unwrap_or_else(|| "plan.json".to_string())),
+        Err(e) => return usage_error(&e),
+    };
+    let result = match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["compute", rest @ ..] => compute(rest).map(|plan| (plan, true)),
+        ["event", "reject", rest @ ..] => reject(&state, rest).map(|plan| (plan, true)),
+        ["show"] => load_plan(&state)
+            .map(|plan| (plan, false))
+            .map_err(|e| CliError::Failed(format!("{}: {e}", state.display()))),
+        _ => Err(CliError::Usage("unknown command".to_string())),
+    };
+    let outcome = result.and_then(|(plan, changed)| {
+        if changed {
+            save_plan(&plan, &state)
+                .map_err(|e| CliError::Failed(format!("{}: {e}", state.display())))?;
+        }
+        print!("{}", PlanDisplay(&plan));
+        Ok(())
+    });
+    match outcome {
+        Ok(()) => 0,
+        Err(CliError::Usage(e)) => usage_error(&e),
+        Err(CliError::Failed(e)) => {
+            eprintln!("error: {e}");
+            1
+        }
+    }
+}
+
+enum CliError {
+    Usage(String),
+    Failed(String),
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("error: {message}\n{USAGE}");
+    2
+}
+
+fn compute(args: &[&str]) -> Result<Plan, CliError> {
+    let riders: u32 = flag(args, "--riders")?;
+    let orders: u64 = flag(args, "--orders")?;
+    if riders == 0 && orders > 0 {
+        return Err(CliError::Failed(
+            "orders need at least one rider".to_string(),
+        ));
+    }
+    let riders: Vec<Rider> = (1..=riders)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (1..=orders)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    Ok(compute_plan(&riders, &orders))
+}
+
+fn reject(state: &Path, args: &[&str]) -> Result<Plan, CliError> {
+    let event = Event::RiderRejected {
+        rider_id: flag(args, "--rider")?,
+        order_id: flag(args, "--order")?,
+    };
+    let plan =
+        load_plan(state).map_err(|e| CliError::Failed(format!("{}: {e}", state.display())))?;
+    Ok(process_event(plan, event).0)
+}
+
+// Removes `name VALUE` from anywhere in the arguments
+fn take_flag(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let Some(idx) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if idx + 1 >= args.len() {
+        return Err(format!("{name} needs a value"));
+    }
+    let value = args.remove(idx + 1);
+    args.remove(idx);
+    Ok(Some(value))
+}
+
+fn flag<T: std::str::FromStr>(args: &[&str], name: &str) -> Result<T, CliError> {
+    let bad = || CliError::Usage(format!("{name} needs a number"));
+    let idx = args.iter().position(|arg| *arg == name).ok_or_else(bad)?;
+    args.get(idx + 1)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(bad)
+}
@@ -0,0 +1,206 @@
+// Request handlers for serving the dispatcher over HTTP. They take and return
+// JSON bodies as strings so any server can mount them; failures are answered
+// with {"error": "..."} instead of a plan.
+//
+// A plan is an object from rider id to its order queue, {"1":[10,11],"2":[]}.
+
+use crate::json::{Json, JsonError};
+use crate::{compute_plan, plan_from_json, plan_to_json, process_event, Event, Order, Plan, Rider};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    Json(JsonError),
+    // A field that is missing or holds the wrong kind of value
+    BadField(&'static str),
+    UnknownEventType(String),
+    // Orders were sent without any rider to take them
+    NoRiders,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Json(e) => write!(f, "malformed JSON: {e}"),
+            RequestError::BadField(name) => write!(f, "missing or invalid field {name:?}"),
+            RequestError::UnknownEventType(name) => write!(f, "unknown event type {name:?}"),
+            RequestError::NoRiders => write!(f, "orders need at least one rider"),
+        }
+    }
+}
+
+impl From<JsonError> for RequestError {
+    fn from(e: JsonError) -> Self {
+        RequestError::Json(e)
+    }
+}
+
+// {"riders": [{"id": 1}, ...], "orders": [{"id": 10}, ...]} in, plan out
+pub fn handle_compute(body: &str) -> String {
+    respond(compute(body))
+}
+
+// Applies one event, e.g. {"type": "RiderRejected", "rider_id": 1,
+// "order_id": 10}, and answers with the resulting plan
+pub fn handle_event(plan_json: &str, event_json: &str) -> String {
+    respond(apply_event(plan_json, event_json))
+}
+
+fn respond(result: Result<Json, RequestError>) -> String {
+    match result {
+        Ok(json) => json.to_string(),
+        Err(e) => {
+            Json::Object(vec![("error".to_string(), Json::String(e.to_string()))]).to_string()
+        }
+    }
+}
+
+fn compute(body: &str) -> Result<Json, RequestError> {
+    let body = Json::parse(body)?;
+    let riders = list(&body, "riders")?
+        .iter()
+        .map(|rider| {
+            Ok(Rider {
+                id: field(rider, "id", Json::as_u32)?,
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<_>, RequestError>>()?;
+    let orders = list(&body, "orders")?
+        .iter()
+        .map(|order| {
+            Ok(Order {
+                id: field(order, "id", Json::as_u64)?,
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<_>, RequestError>>()?;
+    if riders.is_empty() && !orders.is_empty() {
+        return Err(RequestError::NoRiders);
+    }
+    Ok(plan_to_json(&compute_plan(&riders, &orders)))
+}
+
+fn apply_event(plan_json: &str, event_json: &str) -> Result<Json, RequestError> {
+    let plan = plan_from_json(&Json::parse(plan_json)?).ok_or(RequestError::BadField("plan"))?;
+    let event = event_from_json(&Json::parse(event_json)?)?;
+    Ok(plan_to_json(&process_event(plan, event).0))
+}
+
+fn list<'a>(json: &'a Json, name: &'static str) -> Result<&'a [Json], RequestError> {
+    json.get(name)
+        .and_then(Json::as_array)
+        .ok_or(RequestError::BadField(name))
+}
+
+fn field<T>(
+    json: &Json,
+    name: &'static str,
+    read: fn(&Json) -> Option<T>,
+) -> Result<T, RequestError> {
+    json.get(name)
+        .and_then(read)
+        .ok_or(RequestError::BadField(name))
+}
+
+fn event_from_json(json: &Json) -> Result<Event, RequestError> {
+    let kind = field(json, "type", |t| t.as_str().map(str::to_string))?;
+    let order_id = || field(json, "order_id", Json::as_u64);
+    let rider_id = || field(json, "rider_id", Json::as_u32);
+    Ok(match kind.as_str() {
+        "RiderRejected" => Event::RiderRejected {
+            rider_id: rider_id()?,
+            order_id: order_id()?,
+        },
+        "OrderCanceled" => Event::OrderCanceled {
+            order_id: order_id()?,
+        },
+        "OrderAdded" => Event::OrderAdded {
+            order_id: order_id()?,
+        },
+        "RiderAdded" => Event::RiderAdded {
+            rider_id: rider_id()?,
+        },
+        "OrderHeld" => Event::OrderHeld {
+            order_id: order_id()?,
+        },
+        "OrderReleased" => Event::OrderReleased {
+            order_id: order_id()?,
+        },
+        "OrderReassigned" => Event::OrderReassigned {
+            order_id: order_id()?,
+            to: field(json, "to", Json::as_u32)?,
+        },
+        _ => return Err(RequestError::UnknownEventType(kind)),
+    })
+}
+
+#[test]
+fn compute_answers_with_the_round_robin_plan() {
+    let response = handle_compute(
+        r#"{"riders": [{"id": 1}, {"id": 2}], "orders": [{"id": 10}, {"id": 11}, {"id": 12}]}"#,
+    );
+    assert_eq!(response, r#"{"1":[10,12],"2":[11]}"#);
+
+    let riders: Vec<Rider> = [1, 2]
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .to_vec();
+    let orders: Vec<Order> = [10, 11, 12]
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .to_vec();
+    let plan = plan_from_json(&Json::parse(&response).unwrap()).unwrap();
+    assert_eq!(plan, compute_plan(&riders, &orders));
+}
+
+#[test]
+fn event_answers_with_the_updated_plan() {
+    let plan = r#"{"1":[10,11],"2":[20]}"#;
+    let response = handle_event(
+        plan,
+        r#"{"type": "OrderReassigned", "order_id": 10, "to": 2}"#,
+    );
+    assert_eq!(response, r#"{"1":[11],"2":[20,10]}"#);
+    let response = handle_event(&response, r#"{"type": "OrderCanceled", "order_id": 20}"#);
+    assert_eq!(response, r#"{"1":[11],"2":[10]}"#);
+    // Nothing is held between requests, so a release can't sneak in an order
+    let response = handle_event(&response, r#"{"type": "OrderReleased", "order_id": 30}"#);
+    assert_eq!(response, r#"{"1":[11],"2":[10]}"#);
+}
+
+#[test]
+fn malformed_requests_get_error_responses() {
+    let error = |message: &str| format!(r#"{{"error":"{message}"}}"#);
+    assert_eq!(
+        handle_compute(r#"{"riders": [{"id": 1}"#),
+        error("malformed JSON: unexpected end of input")
+    );
+    assert_eq!(
+        handle_compute(&"[".repeat(200_000)),
+        error("malformed JSON: nesting too deep at byte 128")
+    );
+    assert_eq!(
+        handle_compute(r#"{"riders": [{"id": -1}], "orders": []}"#),
+        error(r#"missing or invalid field \"id\""#)
+    );
+    assert_eq!(
+        handle_compute(r#"{"riders": [], "orders": [{"id": 10}]}"#),
+        error("orders need at least one rider")
+    );
+    assert_eq!(
+        handle_event(r#"{"1":[10]}"#, r#"{"type": "OrderLost", "order_id": 10}"#),
+        error(r#"unknown event type \"OrderLost\""#)
+    );
+    assert_eq!(
+        handle_event(
+            r#"{"one":[10]}"#,
+            r#"{"type": "OrderCanceled", "order_id": 10}"#
+        ),
+        error(r#"missing or invalid field \"plan\""#)
+    );
+}
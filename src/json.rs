@@ -0,0 +1,415 @@
+// Just enough JSON for the dispatcher's wire formats. Numbers keep their
+// source text, so u64 ids survive without going through f64.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    // Keys in document order, duplicates kept
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JsonError {
+    UnexpectedEnd,
+    // Byte offset and the character found there
+    UnexpectedChar(usize, char),
+    BadEscape(usize),
+    TrailingCharacters(usize),
+    // An array or object opened at this byte nests deeper than MAX_DEPTH
+    TooDeep(usize),
+}
+
+// Parsing recurses per nesting level, so untrusted input gets a cap well
+// short of the stack running out
+const MAX_DEPTH: usize = 128;
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            JsonError::UnexpectedChar(at, c) => write!(f, "unexpected {c:?} at byte {at}"),
+            JsonError::BadEscape(at) => write!(f, "bad escape sequence at byte {at}"),
+            JsonError::TrailingCharacters(at) => write!(f, "trailing characters at byte {at}"),
+            JsonError::TooDeep(at) => write!(f, "nesting too deep at byte {at}"),
+        }
+    }
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser {
+            text,
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != text.len() {
+            return Err(JsonError::TrailingCharacters(parser.pos));
+        }
+        Ok(value)
+    }
+
+    // First value under `key` when this is an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        self.as_u64().and_then(|n| u32::try_from(n).ok())
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        match self {
+            Json::Object(fields) => Some(fields),
+            _ => None,
+        }
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        Json::Number(n.to_string())
+    }
+}
+
+impl From<u32> for Json {
+    fn from(n: u32) -> Json {
+        Json::Number(n.to_string())
+    }
+}
+
+// Shortest text that parses back to the same value. JSON has no NaN or
+// infinities, those become null.
+impl From<f64> for Json {
+    fn from(n: f64) -> Json {
+        if n.is_finite() {
+            Json::Number(n.to_string())
+        } else {
+            Json::Null
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+// Compact output, no whitespace between tokens
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Number(text) => write!(f, "{text}"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (idx, (key, value)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    // Arrays and objects currently open
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Result<char, JsonError> {
+        let c = self.peek().ok_or(JsonError::UnexpectedEnd)?;
+        self.pos += c.len_utf8();
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        let at = self.pos;
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => Err(JsonError::UnexpectedChar(at, c)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek().ok_or(JsonError::UnexpectedEnd)? {
+            '{' => self.nested(Self::object),
+            '[' => self.nested(Self::array),
+            '"' => self.string().map(Json::String),
+            '-' | '0'..='9' => self.number(),
+            't' => self.literal("true", Json::Bool(true)),
+            'f' => self.literal("false", Json::Bool(false)),
+            'n' => self.literal("null", Json::Null),
+            c => Err(JsonError::UnexpectedChar(self.pos, c)),
+        }
+    }
+
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Json, JsonError>,
+    ) -> Result<Json, JsonError> {
+        if self.depth == MAX_DEPTH {
+            return Err(JsonError::TooDeep(self.pos));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        for expected in word.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            let at = self.pos;
+            match self.next()? {
+                ',' => continue,
+                '}' => return Ok(Json::Object(fields)),
+                c => return Err(JsonError::UnexpectedChar(at, c)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            let at = self.pos;
+            match self.next()? {
+                ',' => continue,
+                ']' => return Ok(Json::Array(items)),
+                c => return Err(JsonError::UnexpectedChar(at, c)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let at = self.pos;
+            match self.next()? {
+                '"' => return Ok(out),
+                '\\' => out.push(match self.next()? {
+                    '"' => '"',
+                    '\\' => '\\',
+                    '/' => '/',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'u' => self.unicode_escape(at)?,
+                    _ => return Err(JsonError::BadEscape(at)),
+                }),
+                c if (c as u32) < 0x20 => return Err(JsonError::UnexpectedChar(at, c)),
+                c => out.push(c),
+            }
+        }
+    }
+
+    // The four hex digits after \u, plus a low surrogate escape when they
+    // are a high surrogate
+    fn unicode_escape(&mut self, at: usize) -> Result<char, JsonError> {
+        let first = self.hex4(at)?;
+        let code = if (0xd800..0xdc00).contains(&first) {
+            if self.text.get(self.pos..self.pos + 2) != Some("\\u") {
+                return Err(JsonError::BadEscape(at));
+            }
+            self.pos += 2;
+            let second = self.hex4(at)?;
+            if !(0xdc00..0xe000).contains(&second) {
+                return Err(JsonError::BadEscape(at));
+            }
+            0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or(JsonError::BadEscape(at))
+    }
+
+    fn hex4(&mut self, at: usize) -> Result<u32, JsonError> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .ok_or(JsonError::UnexpectedEnd)?;
+        self.pos += 4;
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(JsonError::BadEscape(at));
+        }
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        let digits = |parser: &mut Self| -> Result<(), JsonError> {
+            match parser.peek() {
+                Some('0'..='9') => {}
+                Some(c) => return Err(JsonError::UnexpectedChar(parser.pos, c)),
+                None => return Err(JsonError::UnexpectedEnd),
+            }
+            while let Some('0'..='9') = parser.peek() {
+                parser.pos += 1;
+            }
+            Ok(())
+        };
+        digits(self)?;
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            digits(self)?;
+        }
+        if let Some('e' | 'E') = self.peek() {
+            self.pos += 1;
+            if let Some('+' | '-') = self.peek() {
+                self.pos += 1;
+            }
+            digits(self)?;
+        }
+        Ok(Json::Number(self.text[start..self.pos].to_string()))
+    }
+}
+
+#[test]
+fn json_round_trips_through_text() {
+    let text = r#"{"id":18446744073709551615,"name":"a \"b\"\n\u00e9","tags":[true,null,-1.5e3],"empty":{}}"#;
+    let json = Json::parse(text).unwrap();
+    assert_eq!(json.get("id").and_then(Json::as_u64), Some(u64::MAX));
+    assert_eq!(json.get("name").and_then(Json::as_str), Some("a \"b\"\né"));
+    assert_eq!(
+        json.get("tags").and_then(|t| t.as_array()?[2].as_f64()),
+        Some(-1500.0)
+    );
+    assert_eq!(Json::parse(&json.to_string()), Ok(json));
+}
+
+#[test]
+fn malformed_json_is_rejected() {
+    assert_eq!(Json::parse(""), Err(JsonError::UnexpectedEnd));
+    assert_eq!(Json::parse("[1,]"), Err(JsonError::UnexpectedChar(3, ']')));
+    assert_eq!(
+        Json::parse("{\"a\" 1}"),
+        Err(JsonError::UnexpectedChar(5, '1'))
+    );
+    assert_eq!(Json::parse("\"\\x\""), Err(JsonError::BadEscape(1)));
+    assert_eq!(Json::parse("1 2"), Err(JsonError::TrailingCharacters(2)));
+    assert_eq!(Json::parse("-"), Err(JsonError::UnexpectedEnd));
+    let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+    assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+    assert_eq!(
+        Json::parse(&nested(MAX_DEPTH + 1)),
+        Err(JsonError::TooDeep(MAX_DEPTH))
+    );
+}
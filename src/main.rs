@@ -1,30 +1,171 @@
-#![allow(unused, unused_variables, unused_imports)]
+#![allow(unused, unused_variables, unused_imports, non_local_definitions)]
 
 use itertools::Itertools;
 use proptest::prelude::*;
+use proptest::strategy::Strategy as _;
 use proptest_derive::Arbitrary;
 use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "cli")]
+mod cli;
+#[cfg(feature = "http")]
+mod dispatch_http;
+mod json;
+mod scenario;
 
 // Maps rider id to sequence of order ids
-type Plan = HashMap<u32, Vec<u64>>;
+type Plan = PlanMap<u32, Vec<u64>>;
 
-#[derive(Arbitrary, Clone, Debug)]
+// Rider and order ids aren't attacker controlled, so the fast-hash feature
+// trades SipHash's DoS resistance for FxHash's speed on large plans
+#[cfg(feature = "fast-hash")]
+type PlanMap<K, V> = HashMap<K, V, FxBuildHasher>;
+#[cfg(not(feature = "fast-hash"))]
+type PlanMap<K, V> = HashMap<K, V>;
+
+type FxBuildHasher = std::hash::BuildHasherDefault<FxHasher>;
+
+// The multiply-rotate hash rustc uses for its own tables
+#[derive(Clone, Copy, Debug, Default)]
+struct FxHasher {
+    hash: u64,
+}
+
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl FxHasher {
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl std::hash::Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add_to_hash(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add_to_hash(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+#[derive(Arbitrary, Clone, Debug, Default)]
 struct Rider {
     id: u32,
+    // Max number of orders the rider takes, None means unlimited
+    #[proptest(strategy = "prop::option::of(0..10u32)")]
+    capacity: Option<u32>,
+    // (latitude, longitude) in degrees
+    location: Option<(f64, f64)>,
+    skill: u8,
+    #[proptest(strategy = "0..4u16")]
+    zone: u16,
+    // Bitmask of what the rider can carry (cold box, alcohol license, ...)
+    tags: u32,
+    // Working hours as [shift_start, shift_end)
+    #[proptest(strategy = "0..100u64")]
+    shift_start: u64,
+    #[proptest(strategy = "0..100u64")]
+    shift_end: u64,
+    // Customer rating, higher is better
+    #[proptest(strategy = "0.0..5.0f64")]
+    rating: f64,
+    // How far from their location the rider will go, None means anywhere
+    #[proptest(strategy = "prop::option::of(0.0..5000.0f64)")]
+    max_radius_km: Option<f64>,
 }
 
-#[derive(Arbitrary, Clone, Debug)]
+impl Rider {
+    // Whether a rider already holding `load` orders can take one more
+    fn has_room(&self, load: usize) -> bool {
+        self.capacity
+            .is_none_or(|capacity| load < capacity as usize)
+    }
+
+    fn on_shift(&self, now: u64) -> bool {
+        (self.shift_start..self.shift_end).contains(&now)
+    }
+
+    // Whether `to` is inside the rider's radius; without a location or a
+    // radius there is nothing to measure against
+    fn within_radius(&self, to: (f64, f64)) -> bool {
+        match (self.location, self.max_radius_km) {
+            (Some(from), Some(radius)) => haversine_km(from, to) <= radius,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Arbitrary, Clone, Debug, Default)]
 struct Order {
     id: u64,
+    weight: u32,
+    // Orders to the same address are bundled on one rider when possible
+    address_id: Option<u64>,
+    // (latitude, longitude) in degrees
+    location: Option<(f64, f64)>,
+    // Time after which the order is auto-canceled if still in the plan
+    deadline: Option<u64>,
+    // Minimum rider skill needed to deliver the order
+    complexity: u8,
+    #[proptest(strategy = "0..4u16")]
+    zone: u16,
+    // Tags the rider must have, all of them
+    required_tags: u32,
+    // Higher goes first
+    #[proptest(strategy = "0..4u8")]
+    priority: u8,
+    // When the order came in, on the same clock as deadline
+    created_at: u64,
+    // The order this one depends on; canceling that one cancels this too
+    after: Option<u64>,
+    // Expected time to deliver, for balancing by time instead of count
+    #[proptest(strategy = "0..120u32")]
+    service_minutes: u32,
+    // What the order is worth, for balancing revenue
+    #[proptest(strategy = "0..1000u32")]
+    value: u32,
+}
+
+// The order at `i`, None past the end instead of a panic
+#[must_use]
+fn nth_order(orders: &[Order], i: usize) -> Option<&Order> {
+    orders.get(i)
 }
 
+// Round-robin in input order. Without riders nothing is assigned.
+#[must_use]
 fn compute_plan(riders: &[Rider], orders: &[Order]) -> Plan {
     let mut plan = Plan::default();
     /* 1st implementation */
     /*
     let mut next_order_idx = 0;
     for rider in riders {
-        plan.insert(rider.id, vec![orders[next_order_idx].id]);
+        let Some(order) = nth_order(orders, next_order_idx) else {
+            break;
+        };
+        plan.insert(rider.id, vec![order.id]);
         next_order_idx += 1;
     }
     */
@@ -34,32 +175,409 @@ fn compute_plan(riders: &[Rider], orders: &[Order]) -> Plan {
     /*
     let mut next_order_idx = 0;
     for rider in riders {
-        plan.insert(rider.id, vec![orders[next_order_idx].id]);
+        let Some(order) = nth_order(orders, next_order_idx) else {
+            break;
+        };
+        plan.insert(rider.id, vec![order.id]);
         next_order_idx += 1;
     }
-    for order in &orders[next_order_idx..] {
-        plan.entry(riders[0].id).or_default().push(order.id);
+    if let Some(first) = riders.first() {
+        for order in orders.get(next_order_idx..).unwrap_or_default() {
+            plan.entry(first.id).or_default().push(order.id);
+        }
     }
     */
     /* end */
 
     /* 3rd implementation */
+    plan = compute_plan_observed(riders, orders, &mut |_, _| {});
+    /* end */
+    plan
+}
+
+// compute_plan, calling `on_assign(order_id, rider_id)` for each assignment as
+// it is made
+#[must_use]
+fn compute_plan_observed(
+    riders: &[Rider],
+    orders: &[Order],
+    on_assign: &mut dyn FnMut(u64, u32),
+) -> Plan {
+    let mut plan = Plan::default();
+    if riders.is_empty() {
+        return plan;
+    }
     let mut next_order_idx = 0;
     loop {
-        for rider_idx in 0..riders.len() {
-            if next_order_idx >= orders.len() {
+        for rider in riders {
+            let Some(order) = nth_order(orders, next_order_idx) else {
                 return plan;
-            }
-            plan.entry(riders[rider_idx].id)
-                .or_default()
-                .push(orders[next_order_idx].id);
+            };
+            plan.entry(rider.id).or_default().push(order.id);
+            on_assign(order.id, rider.id);
             next_order_idx += 1;
         }
     }
-    /* end */
+}
+
+// compute_plan, plus the ids of riders left without an order because the
+// orders ran out, in rider order
+#[must_use]
+fn compute_plan_report_idle(riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u32>) {
+    let plan = compute_plan(riders, orders);
+    let idle = riders
+        .iter()
+        .map(|r| r.id)
+        .filter(|id| !plan.contains_key(id))
+        .unique()
+        .collect();
+    (plan, idle)
+}
+
+// The order compute_plan_sorted hands out orders in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OrderSort {
+    #[default]
+    AsGiven,
+    // Ascending id, so the plan doesn't depend on how the input was ordered
+    ById,
+    // Highest priority first, input order among equals
+    ByPriority,
+}
+
+// compute_plan after putting the orders in `sort` order
+#[must_use]
+fn compute_plan_sorted(riders: &[Rider], orders: &[Order], sort: OrderSort) -> Plan {
+    let sorted: Vec<Order> = match sort {
+        OrderSort::AsGiven => return compute_plan(riders, orders),
+        OrderSort::ById => orders.iter().cloned().sorted_by_key(|o| o.id).collect(),
+        OrderSort::ByPriority => orders
+            .iter()
+            .cloned()
+            .sorted_by_key(|o| std::cmp::Reverse(o.priority))
+            .collect(),
+    };
+    compute_plan(riders, &sorted)
+}
+
+// Round-robin over orders from the highest priority tier down, input order
+// within a tier. The rider rotation carries over between tiers, so every tier
+// is spread evenly on its own and no rider collects all the urgent work.
+// Without riders nothing is assigned.
+#[must_use]
+fn compute_plan_tiered(riders: &[Rider], orders: &[Order]) -> Plan {
+    let mut plan = Plan::default();
+    let by_tier = orders
+        .iter()
+        .sorted_by_key(|o| std::cmp::Reverse(o.priority));
+    for (rider, order) in riders.iter().cycle().zip(by_tier) {
+        plan.entry(rider.id).or_default().push(order.id);
+    }
+    plan
+}
+
+// Splits the orders into consecutive waves of `wave_size` (the last one may be
+// shorter) and plans each wave on its own with compute_plan, so every wave is
+// balanced across the riders. A zero `wave_size` plans no waves.
+#[must_use]
+fn compute_plan_waves(riders: &[Rider], orders: &[Order], wave_size: usize) -> Vec<Plan> {
+    if wave_size == 0 {
+        return Vec::new();
+    }
+    orders
+        .chunks(wave_size)
+        .map(|wave| compute_plan(riders, wave))
+        .collect()
+}
+
+// Round-robin like compute_plan, but riders at capacity are skipped. Once
+// every rider is full the remaining orders are returned as leftovers.
+#[must_use]
+fn compute_plan_capped_rr(riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+    let mut plan = Plan::default();
+    let mut loads = vec![0; riders.len()];
+    let mut next_rider_idx = 0;
+    let mut remaining = orders.iter();
+    while let Some(order) = remaining.next() {
+        let with_room = (0..riders.len())
+            .map(|offset| (next_rider_idx + offset) % riders.len())
+            .find_map(|idx| {
+                let rider = riders.get(idx)?;
+                let load = loads.get(idx).copied().unwrap_or(0);
+                rider.has_room(load).then_some((idx, rider))
+            });
+        let Some((rider_idx, rider)) = with_room else {
+            let leftovers = std::iter::once(order).chain(remaining);
+            return (plan, leftovers.map(|o| o.id).collect());
+        };
+        plan.entry(rider.id).or_default().push(order.id);
+        if let Some(load) = loads.get_mut(rider_idx) {
+            *load += 1;
+        }
+        next_rider_idx = rider_idx + 1;
+    }
+    (plan, vec![])
+}
+
+// compute_plan_capped_rr, handing each order nobody has room for to
+// `on_leftover` by ascending id, so the caller decides whether to log, retry
+// or dead-letter it
+#[must_use]
+fn compute_plan_with_leftover_handler(
+    riders: &[Rider],
+    orders: &[Order],
+    on_leftover: &mut dyn FnMut(u64),
+) -> Plan {
+    let (plan, leftovers) = compute_plan_capped_rr(riders, orders);
+    leftovers.into_iter().sorted().for_each(on_leftover);
+    plan
+}
+
+// compute_plan_capped_rr with whatever doesn't fit queued on
+// `overflow_rider`, over their capacity if need be. The overflow rider
+// doesn't have to be one of `riders`; if they are, they take their
+// round-robin share first.
+#[must_use]
+fn compute_plan_with_overflow(riders: &[Rider], orders: &[Order], overflow_rider: u32) -> Plan {
+    let (mut plan, leftovers) = compute_plan_capped_rr(riders, orders);
+    if !leftovers.is_empty() {
+        plan.entry(overflow_rider).or_default().extend(leftovers);
+    }
+    plan
+}
+
+// Least-loaded among the riders skilled enough for each order. Orders above
+// every rider's skill are returned as leftovers.
+#[must_use]
+fn compute_plan_skill(riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+    let mut plan = Plan::default();
+    let mut leftovers = Vec::new();
+    for order in orders {
+        let least_loaded = riders
+            .iter()
+            .filter(|r| r.skill >= order.complexity)
+            .min_by_key(|r| (plan.get(&r.id).map_or(0, |o| o.len()), r.id));
+        match least_loaded {
+            Some(rider) => plan.entry(rider.id).or_default().push(order.id),
+            None => leftovers.push(order.id),
+        }
+    }
+    (plan, leftovers)
+}
+
+// Assigns the order to the rider with the lowest cost, ties going to the lowest rider id.
+// Returns None when the plan has no riders.
+fn assign_with<F: Fn(&Plan, u32) -> i64>(plan: &mut Plan, order_id: u64, cost: F) -> Option<u32> {
+    let rider_id = plan
+        .keys()
+        .cloned()
+        .min_by_key(|rider_id| (cost(plan, *rider_id), *rider_id))?;
+    plan.entry(rider_id).or_default().push(order_id);
+    Some(rider_id)
+}
+
+fn by_count(plan: &Plan, rider_id: u32) -> i64 {
+    plan.get(&rider_id).map_or(0, |orders| orders.len() as i64)
+}
+
+// Total weight of the rider's orders; orders missing from `orders` weigh nothing
+fn by_weight(orders: &[Order]) -> impl Fn(&Plan, u32) -> i64 {
+    let weights: HashMap<u64, u32> = orders.iter().map(|o| (o.id, o.weight)).collect();
+    move |plan, rider_id| {
+        plan.get(&rider_id).map_or(0, |assigned| {
+            assigned
+                .iter()
+                .map(|order_id| weights.get(order_id).cloned().unwrap_or(0) as i64)
+                .sum()
+        })
+    }
+}
+
+// Least-loaded assignment by order count
+fn assign_one(plan: &mut Plan, order_id: u64) -> Option<u32> {
+    assign_with(plan, order_id, by_count)
+}
+
+// How often assign_one_with_retry checks on a rider who is briefly
+// unreachable before giving the order to someone else
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RetryPolicy {
+    // Checks in all, the first one included
+    max_attempts: u8,
+}
+
+// assign_one, but the least-loaded rider only gets the order once
+// `available` says they can be reached, asked up to `policy.max_attempts`
+// times. After that the order goes to the least-loaded other rider who is
+// available right away. None when nobody is.
+fn assign_one_with_retry(
+    plan: &mut Plan,
+    order_id: u64,
+    policy: RetryPolicy,
+    available: impl Fn(u32) -> bool,
+) -> Option<u32> {
+    let by_load: Vec<u32> = plan
+        .keys()
+        .cloned()
+        .sorted_by_key(|rider_id| (by_count(plan, *rider_id), *rider_id))
+        .collect();
+    let preferred = *by_load.first()?;
+    let rider_id = if (0..policy.max_attempts).any(|_| available(preferred)) {
+        preferred
+    } else {
+        by_load
+            .iter()
+            .skip(1)
+            .cloned()
+            .find(|rider_id| available(*rider_id))?
+    };
+    plan.entry(rider_id).or_default().push(order_id);
+    Some(rider_id)
+}
+
+// Line numbers start at 1
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum CsvError {
+    Io {
+        line: usize,
+        kind: std::io::ErrorKind,
+    },
+    // Not exactly the five columns of stream_orders
+    Columns {
+        line: usize,
+    },
+    BadField {
+        line: usize,
+        column: &'static str,
+    },
+}
+
+fn csv_field<T: std::str::FromStr>(
+    value: &str,
+    line: usize,
+    column: &'static str,
+) -> Result<T, CsvError> {
+    value
+        .parse()
+        .map_err(|_| CsvError::BadField { line, column })
+}
+
+// Reads `id,weight,zone,priority,deadline` lines one at a time, with an empty
+// deadline for orders that have none. Blank lines are skipped. Reading goes
+// on after a bad line, so callers choose whether to stop at the first error.
+fn stream_orders<R: std::io::BufRead>(r: R) -> impl Iterator<Item = Result<Order, CsvError>> {
+    r.lines().enumerate().filter_map(|(idx, text)| {
+        let line = idx + 1;
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                return Some(Err(CsvError::Io {
+                    line,
+                    kind: e.kind(),
+                }))
+            }
+        };
+        if text.trim().is_empty() {
+            return None;
+        }
+        let columns: Vec<&str> = text.split(',').map(str::trim).collect();
+        let [id, weight, zone, priority, deadline] = columns[..] else {
+            return Some(Err(CsvError::Columns { line }));
+        };
+        let order = (|| {
+            Ok(Order {
+                id: csv_field(id, line, "id")?,
+                weight: csv_field(weight, line, "weight")?,
+                zone: csv_field(zone, line, "zone")?,
+                priority: csv_field(priority, line, "priority")?,
+                deadline: match deadline {
+                    "" => None,
+                    deadline => Some(csv_field(deadline, line, "deadline")?),
+                },
+                ..Default::default()
+            })
+        })();
+        Some(order)
+    })
+}
+
+// Round-robin as orders arrive, without collecting them first: each goes to
+// the least-loaded rider, lowest id on ties. For riders in ascending id order
+// that is the plan compute_plan makes. Without riders nothing is assigned.
+#[must_use]
+fn compute_plan_streaming(riders: &[Rider], orders: impl Iterator<Item = Order>) -> Plan {
+    let mut plan: Plan = riders.iter().map(|r| (r.id, vec![])).collect();
+    for order in orders {
+        assign_one(&mut plan, order.id);
+    }
+    plan.retain(|_, orders| !orders.is_empty());
     plan
 }
 
+// Places newly arrived orders on the least-loaded riders without touching
+// what is already assigned. Nothing is placed when the plan has no riders.
+fn add_orders(plan: &mut Plan, new_orders: &[Order]) {
+    for order in new_orders {
+        assign_one(plan, order.id);
+    }
+}
+
+fn arb_plan() -> impl proptest::strategy::Strategy<Value = Plan> {
+    any::<HashMap<u32, Vec<u64>>>().prop_map(|plan| plan.into_iter().collect())
+}
+
+fn arb_location() -> impl proptest::strategy::Strategy<Value = (f64, f64)> {
+    (-90.0..90.0, -180.0..180.0)
+}
+
+// Riders and orders with locations and no capacity limits, ids not necessarily unique
+fn arb_located_rider() -> impl proptest::strategy::Strategy<Value = Rider> {
+    (any::<u32>(), arb_location()).prop_map(|(id, location)| Rider {
+        id,
+        location: Some(location),
+        ..Default::default()
+    })
+}
+
+fn arb_located_order() -> impl proptest::strategy::Strategy<Value = Order> {
+    (any::<u64>(), arb_location()).prop_map(|(id, location)| Order {
+        id,
+        location: Some(location),
+        ..Default::default()
+    })
+}
+
+// Events that reference the plan's own riders and orders, plus fresh ids for
+// additions. The plan needs at least one order.
+fn arb_event_for(plan: &Plan) -> impl proptest::strategy::Strategy<Value = Event> {
+    let riders: Vec<u32> = plan.keys().cloned().sorted().collect();
+    let orders: Vec<u64> = plan.values().flatten().cloned().sorted().dedup().collect();
+    let assignments: Vec<(u32, u64)> = plan
+        .iter()
+        .flat_map(|(rider_id, orders)| orders.iter().map(move |order_id| (*rider_id, *order_id)))
+        .sorted()
+        .collect();
+    let known_riders: HashSet<u32> = riders.iter().cloned().collect();
+    let known_orders: HashSet<u64> = orders.iter().cloned().collect();
+    prop_oneof![
+        prop::sample::select(assignments)
+            .prop_map(|(rider_id, order_id)| Event::RiderRejected { rider_id, order_id }),
+        prop::sample::select(orders.clone()).prop_map(|order_id| Event::OrderCanceled { order_id }),
+        any::<u64>()
+            .prop_filter("order already planned", move |id| !known_orders
+                .contains(id))
+            .prop_map(|order_id| Event::OrderAdded { order_id }),
+        any::<u32>()
+            .prop_filter("rider already planned", move |id| !known_riders
+                .contains(id))
+            .prop_map(|rider_id| Event::RiderAdded { rider_id }),
+        prop::sample::select(orders.clone()).prop_map(|order_id| Event::OrderHeld { order_id }),
+        prop::sample::select(orders.clone()).prop_map(|order_id| Event::OrderReleased { order_id }),
+        (prop::sample::select(orders), prop::sample::select(riders))
+            .prop_map(|(order_id, to)| Event::OrderReassigned { order_id, to }),
+    ]
+}
+
 #[derive(Arbitrary, Clone, Debug)]
 enum TestEvent {
     RiderRejected {
@@ -69,9 +587,29 @@ enum TestEvent {
     OrderCanceled {
         which_order: usize,
     },
+    RiderAdded {
+        offset: u8,
+    },
+    OrderHeld {
+        which_order: usize,
+    },
+    OrderReleased {
+        which_order: usize,
+    },
+}
+
+// Picks `which % len` out of `ids`, or the default id when there is nothing
+// to pick from: an event on an id the plan doesn't have, which processing
+// ignores
+fn pick<T: Copy + Default>(ids: &[T], which: usize) -> T {
+    ids.get(which.checked_rem(ids.len()).unwrap_or(0))
+        .copied()
+        .unwrap_or_default()
 }
 
 impl TestEvent {
+    // Indices wrap around the plan's riders and orders. On an empty plan, or a
+    // rider without orders, the missing id is 0.
     fn into_event(self, plan: &Plan) -> Event {
         match self {
             Self::RiderRejected {
@@ -79,166 +617,6723 @@ impl TestEvent {
                 which_order,
             } => {
                 let all_sorted_riders: Vec<u32> = plan.keys().cloned().sorted().dedup().collect();
-                let len = all_sorted_riders.len();
-                let rider_id = all_sorted_riders[which_rider % len];
-                let orders_of_rider = &plan[&rider_id];
-                let order_id = orders_of_rider[which_order % orders_of_rider.len()];
+                let rider_id = pick(&all_sorted_riders, which_rider);
+                let orders_of_rider = plan.get(&rider_id).map_or(&[][..], Vec::as_slice);
+                let order_id = pick(orders_of_rider, which_order);
                 Event::RiderRejected { rider_id, order_id }
             }
             Self::OrderCanceled { which_order } => {
                 let all_sorted_orders: Vec<u64> =
                     plan.values().flatten().cloned().sorted().dedup().collect();
-                let len = all_sorted_orders.len();
                 Event::OrderCanceled {
-                    order_id: all_sorted_orders[which_order as usize % len],
+                    order_id: pick(&all_sorted_orders, which_order),
+                }
+            }
+            Self::RiderAdded { offset } => {
+                let start = plan.keys().max().map_or(0, |max| max.wrapping_add(1));
+                let rider_id = (0..=u32::MAX)
+                    .map(|i| start.wrapping_add(offset as u32).wrapping_add(i))
+                    .find(|id| !plan.contains_key(id))
+                    .unwrap();
+                Event::RiderAdded { rider_id }
+            }
+            Self::OrderHeld { which_order } | Self::OrderReleased { which_order } => {
+                let all_sorted_orders: Vec<u64> =
+                    plan.values().flatten().cloned().sorted().dedup().collect();
+                let order_id = pick(&all_sorted_orders, which_order);
+                match self {
+                    Self::OrderHeld { .. } => Event::OrderHeld { order_id },
+                    _ => Event::OrderReleased { order_id },
                 }
             }
         }
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Arbitrary, Clone, Copy, Debug, PartialEq, Eq)]
 enum Event {
     RiderRejected { rider_id: u32, order_id: u64 },
     OrderCanceled { order_id: u64 },
+    OrderAdded { order_id: u64 },
+    RiderAdded { rider_id: u32 },
+    // Takes the order out of dispatch without canceling it
+    OrderHeld { order_id: u64 },
+    // Puts a held order back, on the least-loaded rider
+    OrderReleased { order_id: u64 },
+    // Moves the order to the given rider
+    OrderReassigned { order_id: u64, to: u32 },
 }
 
-fn process_event(mut plan: Plan, event: Event) -> Plan {
-    match event {
-        Event::RiderRejected { rider_id, order_id } => {
-            // Move order to other rider
-            /* 1st implementation */
-            /*
-            plan.get_mut(&rider_id)
-                .map(|orders| orders.retain(|v| *v != order_id));
-            if let Some((_, orders)) = plan.iter_mut().find(|(id, _)| **id != rider_id) {
-                orders.push(order_id);
-            }
-            */
-            /* end */
-            /* 2nd implementation */
-            if let Some(orders) = plan.get_mut(&rider_id) {
-                if let Some(idx) = orders.iter().position(|v| *v == order_id) {
-                    orders.remove(idx);
-                    if let Some((_, orders)) = plan.iter_mut().find(|(id, _)| **id != rider_id)
-                    {
-                        orders.push(order_id);
-                    }
-                }
-            }
-            /* end */
-        }
-        Event::OrderCanceled { order_id } => {
-            // Remove order from plan
-            plan.values_mut()
-                .for_each(|orders| orders.retain(|v| *v != order_id));
-        }
-    }
-    plan
+// What applying an event did to the plan
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum EventOutcome {
+    Reassigned {
+        order_id: u64,
+        from: u32,
+        to: u32,
+    },
+    Canceled {
+        order_id: u64,
+        // Rebalancing that followed, only with EventOptions::rebalance_on_cancel
+        moves: Vec<PlanChange>,
+    },
+    Assigned {
+        order_id: u64,
+        rider_id: u32,
+    },
+    RiderAdded {
+        rider_id: u32,
+        moves: Vec<PlanChange>,
+    },
+    Held {
+        order_id: u64,
+    },
+    // The plan was left untouched
+    Ignored {
+        reason: IgnoreReason,
+    },
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IgnoreReason {
+    OrderNotWithRider,
+    NoOtherRider,
+    UnknownOrder,
+    UnknownRider,
+    AlreadyAssigned,
+    AlreadyOnRider,
+    NoRiderAvailable,
+    NotHeld,
+    // The order moved too recently to be moved again
+    Cooldown,
+    // The order is waiting for manual assignment
+    Reserved,
+}
 
+fn ignored(reason: IgnoreReason) -> EventOutcome {
+    EventOutcome::Ignored { reason }
+}
 
+// Removes the order from every rider, returning whether it was planned at all
+fn remove_order(plan: &mut Plan, order_id: u64) -> bool {
+    let mut found = false;
+    for orders in plan.values_mut() {
+        let before = orders.len();
+        orders.retain(|v| *v != order_id);
+        found |= orders.len() != before;
+    }
+    found
+}
 
+// Moves a rejected order to the other rider handed the fewest rejected orders
+// so far, then the least loaded, then the lowest id, so no one rider soaks up
+// every rejection. Riders missing from `rejections_taken` have had none.
+fn reject_order(
+    plan: &mut Plan,
+    rider_id: u32,
+    order_id: u64,
+    rejections_taken: &HashMap<u32, u64>,
+) -> EventOutcome {
+    let Some(idx) = plan
+        .get(&rider_id)
+        .and_then(|orders| orders.iter().position(|v| *v == order_id))
+    else {
+        return ignored(IgnoreReason::OrderNotWithRider);
+    };
+    let to = plan
+        .iter()
+        .filter(|(id, _)| **id != rider_id)
+        .map(|(id, orders)| {
+            let taken = rejections_taken.get(id).cloned().unwrap_or(0);
+            (taken, orders.len(), *id)
+        })
+        .min();
+    let Some((_, _, to)) = to else {
+        return ignored(IgnoreReason::NoOtherRider);
+    };
+    plan.get_mut(&rider_id).unwrap().remove(idx);
+    plan.entry(to).or_default().push(order_id);
+    EventOutcome::Reassigned {
+        order_id,
+        from: rider_id,
+        to,
+    }
+}
 
+// OrderCanceled for every planned order matching `pred`, returning the ids by
+// ascending rider id and then queue order. Riders left without orders stay in
+// the plan, as with a single cancel.
+fn cancel_where<F: Fn(u64) -> bool>(plan: &mut Plan, pred: F) -> Vec<u64> {
+    let mut canceled = vec![];
+    for rider_id in plan.keys().cloned().sorted().collect::<Vec<_>>() {
+        let orders = plan.get_mut(&rider_id).unwrap();
+        canceled.extend(orders.iter().filter(|id| pred(**id)));
+        orders.retain(|id| !pred(*id));
+    }
+    canceled
+}
 
+// Cancels `order_id` and every order that depends on it, directly or through
+// other dependents, returning them ascending and once each, planned or not.
+// Dependency cycles stop where they come back around.
+fn cancel_cascade(plan: &mut Plan, orders: &[Order], order_id: u64) -> Vec<u64> {
+    let mut dependents: HashMap<u64, Vec<u64>> = HashMap::new();
+    for order in orders {
+        if let Some(after) = order.after {
+            dependents.entry(after).or_default().push(order.id);
+        }
+    }
+    let mut cascade = HashSet::from([order_id]);
+    let mut pending = vec![order_id];
+    while let Some(id) = pending.pop() {
+        for dependent in dependents.get(&id).into_iter().flatten() {
+            if cascade.insert(*dependent) {
+                pending.push(*dependent);
+            }
+        }
+    }
+    cancel_where(plan, |id| cascade.contains(&id));
+    cascade.into_iter().sorted().collect()
+}
 
+// The rider holding the order, the lowest id one if a corrupt plan has several
+#[must_use]
+fn rider_of(plan: &Plan, order_id: u64) -> Option<u32> {
+    plan.iter()
+        .filter(|(_, orders)| orders.contains(&order_id))
+        .map(|(rider_id, _)| *rider_id)
+        .min()
+}
 
-proptest! {
-    #[test]
-    fn all_riders_get_orders(riders: Vec<Rider>, orders: Vec<Order>) {
-        prop_assume!(!riders.is_empty());
-        prop_assume!(riders.len() <= orders.len());
-        prop_assume!(riders.iter().map(|r| r.id).all_unique());
-        prop_assume!(orders.iter().map(|o| o.id).all_unique());
-        println!("{} {}", riders.len(), orders.len());
-
-        let plan = compute_plan(&riders, &orders);
-        for rider in riders {
-            assert!(plan.contains_key(&rider.id));
-            assert!(!plan[&rider.id].is_empty());
+// Last-write-wins merge of two replicas of a plan. `ts_a` and `ts_b` hold
+// the logical time each side last assigned an order, 0 when missing. An
+// order held by both sides goes to the rider with the later timestamp, the
+// lower rider id on a tie, so the merge doesn't depend on argument order.
+// Queues come out by timestamp, then order id, and every rider of either
+// side is kept.
+#[must_use]
+fn merge_lww(a: Plan, b: Plan, ts_a: &HashMap<u64, u64>, ts_b: &HashMap<u64, u64>) -> Plan {
+    let mut winners: HashMap<u64, (u64, std::cmp::Reverse<u32>)> = HashMap::new();
+    for (plan, ts) in [(&a, ts_a), (&b, ts_b)] {
+        for (rider_id, orders) in plan {
+            for order_id in orders {
+                let claim = (
+                    ts.get(order_id).copied().unwrap_or(0),
+                    std::cmp::Reverse(*rider_id),
+                );
+                let winner = winners.entry(*order_id).or_insert(claim);
+                *winner = (*winner).max(claim);
+            }
         }
     }
+    let mut merged: Plan = a
+        .keys()
+        .chain(b.keys())
+        .map(|id| (*id, Vec::new()))
+        .collect();
+    for (order_id, (_, std::cmp::Reverse(rider_id))) in winners
+        .into_iter()
+        .sorted_by_key(|(order_id, (ts, _))| (*ts, *order_id))
+    {
+        merged.entry(rider_id).or_default().push(order_id);
+    }
+    merged
+}
 
+#[must_use]
+fn process_event(plan: Plan, event: Event) -> (Plan, EventOutcome) {
+    process_event_v2(plan, event)
+}
+
+// Switches for process_event_with, all off by default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct EventOptions {
+    // Rebalance after a cancel, since the rider who lost the order may now be
+    // short of work. The moves come back in the Canceled outcome.
+    rebalance_on_cancel: bool,
+}
 
+// process_event with `options` applied on top
+#[must_use]
+fn process_event_with(plan: Plan, event: Event, options: EventOptions) -> (Plan, EventOutcome) {
+    match process_event(plan, event) {
+        (mut plan, EventOutcome::Canceled { order_id, .. }) if options.rebalance_on_cancel => {
+            let moves = rebalance(&mut plan);
+            (plan, EventOutcome::Canceled { order_id, moves })
+        }
+        processed => processed,
+    }
+}
 
+// An event that should only move orders around changed which ones are planned
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ConservationError {
+    event: Event,
+    // Ascending, with repeats when an order lost or gained several copies
+    lost: Vec<u64>,
+    gained: Vec<u64>,
+}
 
+// process_event, failing when an event other than an add, cancel, hold or
+// release changes the planned orders: the events_over_time invariant checked
+// at runtime
+fn process_event_checked(
+    plan: Plan,
+    event: Event,
+) -> Result<(Plan, EventOutcome), ConservationError> {
+    check_conservation(plan, event, process_event)
+}
 
+fn check_conservation(
+    plan: Plan,
+    event: Event,
+    handler: impl FnOnce(Plan, Event) -> (Plan, EventOutcome),
+) -> Result<(Plan, EventOutcome), ConservationError> {
+    let conserving = matches!(
+        event,
+        Event::RiderRejected { .. } | Event::RiderAdded { .. } | Event::OrderReassigned { .. }
+    );
+    let live = |plan: &Plan| -> Vec<u64> { plan.values().flatten().cloned().sorted().collect() };
+    let before = if conserving { live(&plan) } else { vec![] };
+    let (plan, outcome) = handler(plan, event);
+    if conserving {
+        let after = live(&plan);
+        let (mut lost, mut gained) = (vec![], vec![]);
+        for diff in before.iter().merge_join_by(&after, |a, b| a.cmp(b)) {
+            match diff {
+                itertools::EitherOrBoth::Left(order_id) => lost.push(*order_id),
+                itertools::EitherOrBoth::Right(order_id) => gained.push(*order_id),
+                itertools::EitherOrBoth::Both(..) => {}
+            }
+        }
+        if !lost.is_empty() || !gained.is_empty() {
+            return Err(ConservationError {
+                event,
+                lost,
+                gained,
+            });
+        }
+    }
+    Ok((plan, outcome))
+}
 
+// The first RiderRejected handling, kept to pin down how it differs from v2.
+// It drops the order from the rider without checking it was there and hands
+// it to the first other rider in map order rather than the least loaded, so a
+// rejection can invent or duplicate an order, strips every copy the rider
+// held, and loses the order when there is no other rider. Other events behave
+// as in v2.
+#[must_use]
+fn process_event_v1(mut plan: Plan, event: Event) -> (Plan, EventOutcome) {
+    let Event::RiderRejected { rider_id, order_id } = event else {
+        return process_event_v2(plan, event);
+    };
+    let removed = plan.get_mut(&rider_id).is_some_and(|orders| {
+        let before = orders.len();
+        orders.retain(|v| *v != order_id);
+        orders.len() != before
+    });
+    let outcome = match plan.iter_mut().find(|(id, _)| **id != rider_id) {
+        Some((to, orders)) => {
+            orders.push(order_id);
+            EventOutcome::Reassigned {
+                order_id,
+                from: rider_id,
+                to: *to,
+            }
+        }
+        None if removed => EventOutcome::Canceled {
+            order_id,
+            moves: vec![],
+        },
+        None => ignored(IgnoreReason::NoOtherRider),
+    };
+    (plan, outcome)
+}
 
+#[must_use]
+fn process_event_v2(mut plan: Plan, event: Event) -> (Plan, EventOutcome) {
+    let outcome = match event {
+        // Without a history of rejections, to the least-loaded other rider
+        Event::RiderRejected { rider_id, order_id } => {
+            reject_order(&mut plan, rider_id, order_id, &HashMap::new())
+        }
+        Event::OrderCanceled { order_id } => {
+            // Remove order from plan
+            if remove_order(&mut plan, order_id) {
+                EventOutcome::Canceled {
+                    order_id,
+                    moves: vec![],
+                }
+            } else {
+                ignored(IgnoreReason::UnknownOrder)
+            }
+        }
+        Event::OrderAdded { order_id } => {
+            if plan.values().flatten().any(|v| *v == order_id) {
+                ignored(IgnoreReason::AlreadyAssigned)
+            } else {
+                match assign_one(&mut plan, order_id) {
+                    Some(rider_id) => EventOutcome::Assigned { order_id, rider_id },
+                    None => ignored(IgnoreReason::NoRiderAvailable),
+                }
+            }
+        }
+        Event::RiderAdded { rider_id } => {
+            // The new rider takes over load from the busiest riders
+            plan.entry(rider_id).or_default();
+            let moves = rebalance(&mut plan);
+            EventOutcome::RiderAdded { rider_id, moves }
+        }
+        // Plans don't know which orders are held, Dispatcher keeps that set,
+        // so without it nothing counts as held and there is nothing to release
+        Event::OrderHeld { order_id } => {
+            if remove_order(&mut plan, order_id) {
+                EventOutcome::Held { order_id }
+            } else {
+                ignored(IgnoreReason::UnknownOrder)
+            }
+        }
+        Event::OrderReleased { .. } => ignored(IgnoreReason::NotHeld),
+        Event::OrderReassigned { order_id, to } => match rider_of(&plan, order_id) {
+            None => ignored(IgnoreReason::UnknownOrder),
+            Some(_) if !plan.contains_key(&to) => ignored(IgnoreReason::UnknownRider),
+            Some(from) if from == to => ignored(IgnoreReason::AlreadyOnRider),
+            Some(from) => {
+                plan.get_mut(&from).unwrap().retain(|v| *v != order_id);
+                plan.entry(to).or_default().push(order_id);
+                EventOutcome::Reassigned { order_id, from, to }
+            }
+        },
+    };
+    (plan, outcome)
+}
 
+// Cancels every planned order whose deadline is before `now`, the clock-driven
+// counterpart of OrderCanceled. Returns the canceled ids in ascending order.
+fn advance_clock(plan: &mut Plan, orders: &[Order], now: u64) -> Vec<u64> {
+    let expired: HashSet<u64> = orders
+        .iter()
+        .filter(|o| o.deadline.is_some_and(|deadline| now > deadline))
+        .map(|o| o.id)
+        .collect();
+    let mut canceled = vec![];
+    for assigned in plan.values_mut() {
+        assigned.retain(|order_id| {
+            let keep = !expired.contains(order_id);
+            if !keep {
+                canceled.push(*order_id);
+            }
+            keep
+        });
+    }
+    canceled.sort_unstable();
+    canceled.dedup();
+    canceled
+}
 
+// Counts events as they are processed and remembers when each order last
+// moved, so that an order that was just moved isn't moved straight back
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct MoveClock {
+    // Events processed so far
+    step: u64,
+    // A rejection can't move an order that moved this many steps ago or less,
+    // zero turns the cooldown off
+    cooldown: u64,
+    // Step of each order's latest move
+    last_moved: HashMap<u64, u64>,
+}
 
-    #[test]
-    fn all_orders_are_assigned(riders: Vec<Rider>, orders: Vec<Order>) {
-        prop_assume!(!riders.is_empty());
-        prop_assume!(riders.len() <= orders.len());
-        prop_assume!(riders.iter().map(|r| r.id).all_unique());
-        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+impl MoveClock {
+    fn new(cooldown: u64) -> Self {
+        MoveClock {
+            cooldown,
+            ..Default::default()
+        }
+    }
 
-        println!("{} {}", riders.len(), orders.len());
+    // Counts `event`, false when it rejects an order that is cooling down
+    fn tick(&mut self, event: Event) -> bool {
+        self.step += 1;
+        let Event::RiderRejected { order_id, .. } = event else {
+            return true;
+        };
+        self.last_moved
+            .get(&order_id)
+            .is_none_or(|moved| self.step - moved > self.cooldown)
+    }
 
-        let plan = compute_plan(&riders, &orders);
-        for order in orders {
-            assert!(plan.values().any(|v| v.contains(&order.id)));
+    // Notes every order `outcome` moved as moved at the current step
+    fn record(&mut self, outcome: &EventOutcome) {
+        match outcome {
+            EventOutcome::Reassigned { order_id, .. } => {
+                self.last_moved.insert(*order_id, self.step);
+            }
+            EventOutcome::RiderAdded { moves, .. } => {
+                for PlanChange::Moved { order_id, .. } in moves {
+                    self.last_moved.insert(*order_id, self.step);
+                }
+            }
+            _ => {}
         }
     }
+}
 
+// process_event one step further along `clock`. A rejection of an order that
+// moved within the cooldown is Ignored with IgnoreReason::Cooldown.
+#[must_use]
+fn process_event_clocked(plan: Plan, event: Event, clock: &mut MoveClock) -> (Plan, EventOutcome) {
+    if !clock.tick(event) {
+        return (plan, ignored(IgnoreReason::Cooldown));
+    }
+    let (plan, outcome) = process_event(plan, event);
+    clock.record(&outcome);
+    (plan, outcome)
+}
 
+#[must_use]
+fn replay(start: Plan, events: &[Event], clock: &mut MoveClock) -> Plan {
+    events.iter().fold(start, |plan, event| {
+        process_event_clocked(plan, *event, clock).0
+    })
+}
 
+// replay, but events that are bound to be Ignored are skipped without
+// touching the plan. Which orders are planned is kept in a set, so spotting
+// a cancel of a gone order or a re-add of a planned one doesn't scan every
+// queue the way process_event has to. Anything not obviously a no-op is
+// applied as usual. Skipped events still count on the clock.
+#[must_use]
+fn replay_fast(start: Plan, events: &[Event], clock: &mut MoveClock) -> Plan {
+    let mut planned: HashSet<u64> = start.values().flatten().cloned().collect();
+    let mut plan = start;
+    for event in events {
+        let no_op = match *event {
+            Event::OrderCanceled { order_id } | Event::OrderHeld { order_id } => {
+                !planned.contains(&order_id)
+            }
+            Event::OrderAdded { order_id } => planned.contains(&order_id) || plan.is_empty(),
+            Event::OrderReleased { .. } => true,
+            Event::OrderReassigned { order_id, to } => {
+                !planned.contains(&order_id) || !plan.contains_key(&to)
+            }
+            Event::RiderRejected { .. } | Event::RiderAdded { .. } => false,
+        };
+        if no_op {
+            clock.tick(*event);
+            continue;
+        }
+        let (next, outcome) = process_event_clocked(plan, *event, clock);
+        plan = next;
+        match outcome {
+            EventOutcome::Canceled { order_id, .. } | EventOutcome::Held { order_id } => {
+                planned.remove(&order_id);
+            }
+            EventOutcome::Assigned { order_id, .. } => {
+                planned.insert(order_id);
+            }
+            _ => {}
+        }
+    }
+    plan
+}
 
+// Applies `events` at most `max_per_tick` at a time, with a snapshot of the
+// plan after every tick. The last snapshot is replay(plan, events, clock);
+// without events, or with a zero `max_per_tick`, there are no ticks and no
+// snapshots.
+#[must_use]
+fn process_events_rate_limited(
+    plan: Plan,
+    events: &[Event],
+    max_per_tick: usize,
+    clock: &mut MoveClock,
+) -> Vec<Plan> {
+    if max_per_tick == 0 {
+        return Vec::new();
+    }
+    let mut plan = plan;
+    events
+        .chunks(max_per_tick)
+        .map(|tick| {
+            plan = replay(std::mem::take(&mut plan), tick, clock);
+            plan.clone()
+        })
+        .collect()
+}
 
+// The first scripted event whose outcome wasn't the recorded one
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ReplayMismatch {
+    index: usize,
+    expected: EventOutcome,
+    actual: EventOutcome,
+}
 
+// Replays a recorded fixture, checking every outcome along the way
+fn replay_expecting(start: Plan, script: &[(Event, EventOutcome)]) -> Result<Plan, ReplayMismatch> {
+    let mut plan = start;
+    for (index, (event, expected)) in script.iter().enumerate() {
+        let (next, actual) = process_event(plan, *event);
+        if actual != *expected {
+            return Err(ReplayMismatch {
+                index,
+                expected: expected.clone(),
+                actual,
+            });
+        }
+        plan = next;
+    }
+    Ok(plan)
+}
 
+// Drops events whose effect a later event makes irrelevant: a rejection of an
+// order that gets canceled, and a reassignment overridden by a later one of
+// the same order. Nothing is dropped across an event that assigns by load
+// (OrderAdded, OrderReleased, RiderAdded), since where the order sat could
+// change that decision. Assumes a valid plan and reassignments to known
+// riders, the replay then ends in a plans_equivalent plan.
+#[must_use]
+fn coalesce(events: &[Event]) -> Vec<Event> {
+    let superseded = |idx: usize, by: &dyn Fn(&Event) -> bool| {
+        events
+            .iter()
+            .skip(idx + 1)
+            .take_while(|e| {
+                !matches!(
+                    e,
+                    Event::OrderAdded { .. }
+                        | Event::OrderReleased { .. }
+                        | Event::RiderAdded { .. }
+                )
+            })
+            .any(by)
+    };
+    events
+        .iter()
+        .enumerate()
+        .filter(|(idx, event)| match **event {
+            Event::RiderRejected { order_id, .. } => !superseded(*idx, &|e| {
+                *e == Event::OrderCanceled { order_id }
+            }),
+            Event::OrderReassigned { order_id, .. } => !superseded(*idx, &|e| {
+                matches!(e, Event::OrderReassigned { order_id: later, .. } if *later == order_id)
+            }),
+            _ => true,
+        })
+        .map(|(_, event)| *event)
+        .collect()
+}
 
+// Orders that get moved back to a rider they already left, a round trip such
+// as A -> B -> C -> A that coalesce could have skipped. Positions come from
+// OrderReassigned targets and from the rider of a RiderRejected (where the
+// order was before it moved on to an unknown rider). An order's trail starts
+// over when it is added, canceled, held or released, and every trail starts
+// over on RiderAdded, whose rebalancing moves orders unseen. Ids come in the
+// order their cycles close, each once; None when there is no cycle.
+#[must_use]
+fn detect_reassignment_cycle(events: &[Event]) -> Option<Vec<u64>> {
+    // Riders each order has been on, and where it is now if known
+    let mut trails: HashMap<u64, (Vec<u32>, Option<u32>)> = HashMap::new();
+    let mut cycles: Vec<u64> = vec![];
+    let mut arrive = |trails: &mut HashMap<u64, (Vec<u32>, Option<u32>)>, order_id, rider_id| {
+        let (visited, current) = trails.entry(order_id).or_default();
+        if *current == Some(rider_id) {
+            return;
+        }
+        if visited.contains(&rider_id) {
+            if !cycles.contains(&order_id) {
+                cycles.push(order_id);
+            }
+            visited.clear();
+        }
+        visited.push(rider_id);
+        *current = Some(rider_id);
+    };
+    for event in events {
+        match *event {
+            Event::OrderReassigned { order_id, to } => arrive(&mut trails, order_id, to),
+            Event::RiderRejected { rider_id, order_id } => {
+                arrive(&mut trails, order_id, rider_id);
+                trails.get_mut(&order_id).unwrap().1 = None;
+            }
+            Event::OrderAdded { order_id }
+            | Event::OrderCanceled { order_id }
+            | Event::OrderHeld { order_id }
+            | Event::OrderReleased { order_id } => {
+                trails.remove(&order_id);
+            }
+            Event::RiderAdded { .. } => trails.clear(),
+        }
+    }
+    (!cycles.is_empty()).then_some(cycles)
+}
 
+// Same orders on the same riders, ignoring queue order and riders without
+// orders
+#[must_use]
+fn plans_equivalent(a: &Plan, b: &Plan) -> bool {
+    let normalized = |plan: &Plan| -> Vec<(u32, Vec<u64>)> {
+        plan.iter()
+            .filter(|(_, orders)| !orders.is_empty())
+            .map(|(rider_id, orders)| (*rider_id, orders.iter().cloned().sorted().collect()))
+            .sorted()
+            .collect()
+    };
+    normalized(a) == normalized(b)
+}
+
+// Events that turn `current` into a plan equivalent to `target`, assuming both
+// hold the same orders once each: RiderAdded for riders that only `target`
+// gives orders to, by ascending id, then OrderReassigned by ascending order id
+// for every order that ends up on a different rider. Reassignments are worked
+// out after the additions, since those rebalance.
+#[must_use]
+fn plan_to_events(current: &Plan, target: &Plan) -> Vec<Event> {
+    let mut events: Vec<Event> = target
+        .iter()
+        .filter(|(rider_id, orders)| !orders.is_empty() && !current.contains_key(rider_id))
+        .map(|(rider_id, _)| *rider_id)
+        .sorted()
+        .map(|rider_id| Event::RiderAdded { rider_id })
+        .collect();
+    let staffed = replay(current.clone(), &events, &mut MoveClock::default());
+    let now = order_holders(&staffed);
+    for (order_id, riders) in order_holders(target).into_iter().sorted() {
+        let Some(to) = riders.first().copied() else {
+            continue;
+        };
+        if now
+            .get(&order_id)
+            .is_some_and(|from| from.first() != Some(&to))
+        {
+            events.push(Event::OrderReassigned { order_id, to });
+        }
+    }
+    events
+}
+
+// One line per event, e.g. "REJECT rider=3 order=42", for pasting failing
+// streams into a unit test
+fn event_log(events: &[Event]) -> String {
+    events
+        .iter()
+        .map(|event| match *event {
+            Event::RiderRejected { rider_id, order_id } => {
+                format!("REJECT rider={rider_id} order={order_id}\n")
+            }
+            Event::OrderCanceled { order_id } => format!("CANCEL order={order_id}\n"),
+            Event::OrderAdded { order_id } => format!("ADD order={order_id}\n"),
+            Event::RiderAdded { rider_id } => format!("ADD_RIDER rider={rider_id}\n"),
+            Event::OrderHeld { order_id } => format!("HOLD order={order_id}\n"),
+            Event::OrderReleased { order_id } => format!("RELEASE order={order_id}\n"),
+            Event::OrderReassigned { order_id, to } => {
+                format!("REASSIGN order={order_id} to={to}\n")
+            }
+        })
+        .collect()
+}
+
+// Line numbers start at 1
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ParseError {
+    UnknownEvent { line: usize },
+    // Missing, unexpected, repeated or unparsable key=value field
+    BadField { line: usize },
+}
+
+// Reads back event_log output, blank lines are skipped
+fn parse_event_log(s: &str) -> Result<Vec<Event>, ParseError> {
+    let mut events = vec![];
+    for (idx, text) in s.lines().enumerate() {
+        let line = idx + 1;
+        let mut words = text.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        let mut fields: HashMap<&str, &str> = HashMap::new();
+        for word in words {
+            let (key, value) = word.split_once('=').ok_or(ParseError::BadField { line })?;
+            if fields.insert(key, value).is_some() {
+                return Err(ParseError::BadField { line });
+            }
+        }
+        let expected: &[&str] = match keyword {
+            "REJECT" => &["rider", "order"],
+            "ADD_RIDER" => &["rider"],
+            "REASSIGN" => &["order", "to"],
+            "CANCEL" | "ADD" | "HOLD" | "RELEASE" => &["order"],
+            _ => return Err(ParseError::UnknownEvent { line }),
+        };
+        if fields.len() != expected.len() {
+            return Err(ParseError::BadField { line });
+        }
+        let order = || -> Result<u64, ParseError> {
+            fields
+                .get("order")
+                .and_then(|v| v.parse().ok())
+                .ok_or(ParseError::BadField { line })
+        };
+        let rider = |key: &str| -> Result<u32, ParseError> {
+            fields
+                .get(key)
+                .and_then(|v| v.parse().ok())
+                .ok_or(ParseError::BadField { line })
+        };
+        events.push(match keyword {
+            "REJECT" => Event::RiderRejected {
+                rider_id: rider("rider")?,
+                order_id: order()?,
+            },
+            "CANCEL" => Event::OrderCanceled { order_id: order()? },
+            "ADD" => Event::OrderAdded { order_id: order()? },
+            "ADD_RIDER" => Event::RiderAdded {
+                rider_id: rider("rider")?,
+            },
+            "HOLD" => Event::OrderHeld { order_id: order()? },
+            "RELEASE" => Event::OrderReleased { order_id: order()? },
+            _ => Event::OrderReassigned {
+                order_id: order()?,
+                to: rider("to")?,
+            },
+        });
+    }
+    Ok(events)
+}
+
+// The starting plan followed by the plan after each event
+#[must_use]
+fn replay_with_snapshots(start: Plan, events: &[Event]) -> Vec<Plan> {
+    let mut plan = start;
+    let mut snapshots = vec![plan.clone()];
+    for event in events {
+        plan = process_event(plan, *event).0;
+        snapshots.push(plan.clone());
+    }
+    snapshots
+}
+
+// Where an order stands at one point of a replay
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OrderState {
+    WithRider(u32),
+    Held,
+    Canceled,
+    // Not in the plan for any other reason, e.g. never added
+    Unplanned,
+}
+
+// The states `order_id` goes through while `events` are replayed from
+// `start`, beginning with where it starts, each change listed once. Held
+// and Canceled stick until the order shows up on a rider again.
+#[must_use]
+fn trace_order(start: &Plan, events: &[Event], order_id: u64) -> Vec<OrderState> {
+    let snapshots = replay_with_snapshots(start.clone(), events);
+    let initial = rider_of(start, order_id).map_or(OrderState::Unplanned, OrderState::WithRider);
+    let mut trace = vec![initial];
+    for (event, plan) in events.iter().zip(snapshots.iter().skip(1)) {
+        let previous = trace.last().copied().unwrap_or(initial);
+        let state = match (rider_of(plan, order_id), event) {
+            (Some(rider_id), _) => OrderState::WithRider(rider_id),
+            (None, Event::OrderCanceled { order_id: id }) if *id == order_id => {
+                OrderState::Canceled
+            }
+            (None, Event::OrderHeld { order_id: id }) if *id == order_id => OrderState::Held,
+            (None, _) if matches!(previous, OrderState::WithRider(_)) => OrderState::Unplanned,
+            (None, _) => previous,
+        };
+        if state != previous {
+            trace.push(state);
+        }
+    }
+    trace
+}
+
+// Worst load spread seen at any point of a replay
+#[must_use]
+fn max_observed_spread(snapshots: &[Plan]) -> usize {
+    snapshots.iter().map(load_spread).max().unwrap_or(0)
+}
+
+// Whether no rider holds any order, riders with empty queues don't count
+#[must_use]
+fn is_empty(plan: &Plan) -> bool {
+    plan.values().all(|orders| orders.is_empty())
+}
+
+// Unassigns everything for a hand-off on shutdown, riders stay with empty
+// queues. Returns the drained order ids in ascending order.
+fn drain_all(plan: &mut Plan) -> Vec<u64> {
+    plan.values_mut()
+        .flat_map(|orders| orders.drain(..))
+        .sorted()
+        .collect()
+}
+
+// Every rider by ascending id with their orders in queue order, the stable
+// export used for printing delivery labels
+#[must_use]
+fn manifest(plan: &Plan) -> Vec<(u32, Vec<u64>)> {
+    plan.iter()
+        .map(|(rider_id, orders)| (*rider_id, orders.clone()))
+        .sorted_by_key(|(rider_id, _)| *rider_id)
+        .collect()
+}
+
+// Prints one line per rider by ascending id, `rider 1: 10 11`
+struct PlanDisplay<'a>(&'a Plan);
+
+impl std::fmt::Display for PlanDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (rider_id, orders) in manifest(self.0) {
+            write!(f, "rider {rider_id}:")?;
+            for order_id in orders {
+                write!(f, " {order_id}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// One `rider=order,order` line per rider by ascending id, with the orders
+// sorted too, so moving one order touches at most two lines of a committed
+// snapshot. Queue order is not kept.
+#[must_use]
+fn plan_to_sorted_lines(plan: &Plan) -> Vec<String> {
+    plan.iter()
+        .sorted_by_key(|(rider_id, _)| **rider_id)
+        .map(|(rider_id, orders)| format!("{rider_id}={}", orders.iter().sorted().join(",")))
+        .collect()
+}
+
+// Reads back plan_to_sorted_lines output. A rider listed twice is a BadField.
+fn plan_from_sorted_lines(lines: &[&str]) -> Result<Plan, ParseError> {
+    let mut plan = Plan::default();
+    for (idx, text) in lines.iter().enumerate() {
+        let line = idx + 1;
+        let bad = ParseError::BadField { line };
+        let (rider_id, orders) = text.split_once('=').ok_or(bad.clone())?;
+        let rider_id: u32 = rider_id.parse().map_err(|_| bad.clone())?;
+        let orders = orders
+            .split_terminator(',')
+            .map(|order_id| order_id.parse().map_err(|_| bad.clone()))
+            .collect::<Result<Vec<u64>, _>>()?;
+        if plan.insert(rider_id, orders).is_some() {
+            return Err(bad);
+        }
+    }
+    Ok(plan)
+}
+
+// Dense riders × orders view of the plan for analytics: row `i` is
+// riders[i], column `j` is orders[j], and a cell is 1 when that rider has
+// that order queued.
+#[must_use]
+fn assignment_matrix(plan: &Plan, riders: &[Rider], orders: &[Order]) -> Vec<Vec<u8>> {
+    riders
+        .iter()
+        .map(|rider| {
+            let queue = plan.get(&rider.id).map_or(&[][..], Vec::as_slice);
+            orders
+                .iter()
+                .map(|order| u8::from(queue.contains(&order.id)))
+                .collect()
+        })
+        .collect()
+}
+
+// Where a rider's own report disagreed with the plan
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Discrepancy {
+    // Planned on the rider, who doesn't have it
+    Missing { rider_id: u32, order_id: u64 },
+    // With the rider, who wasn't planned to have it
+    Unexpected { rider_id: u32, order_id: u64 },
+}
+
+// Makes the plan agree with what riders report having. Reporting riders get
+// exactly their reported queue; an order one of them has is taken off every
+// other rider, and riders who didn't report otherwise keep theirs.
+// Discrepancies come by rider id, Missing before Unexpected, then order id.
+fn reconcile(plan: &mut Plan, reported: &HashMap<u32, Vec<u64>>) -> Vec<Discrepancy> {
+    let claimed: HashSet<u64> = reported.values().flatten().cloned().collect();
+    let rider_ids: Vec<u32> = plan
+        .keys()
+        .chain(reported.keys())
+        .cloned()
+        .sorted()
+        .dedup()
+        .collect();
+    let mut discrepancies = vec![];
+    for rider_id in rider_ids {
+        let planned = plan.get(&rider_id).cloned().unwrap_or_default();
+        let actual = match reported.get(&rider_id) {
+            Some(queue) => queue.clone(),
+            None => planned
+                .iter()
+                .cloned()
+                .filter(|o| !claimed.contains(o))
+                .collect(),
+        };
+        discrepancies.extend(
+            planned
+                .iter()
+                .filter(|o| !actual.contains(o))
+                .sorted()
+                .dedup()
+                .map(|order_id| Discrepancy::Missing {
+                    rider_id,
+                    order_id: *order_id,
+                }),
+        );
+        discrepancies.extend(
+            actual
+                .iter()
+                .filter(|o| !planned.contains(o))
+                .sorted()
+                .dedup()
+                .map(|order_id| Discrepancy::Unexpected {
+                    rider_id,
+                    order_id: *order_id,
+                }),
+        );
+        plan.insert(rider_id, actual);
+    }
+    discrepancies
+}
+
+// Renumbers riders to 0..n in ascending id order for compact exports. The
+// returned map goes from the new id back to the original one.
+#[must_use]
+fn reindex(plan: &Plan) -> (Plan, HashMap<u32, u32>) {
+    let mut dense = Plan::default();
+    let mut original_ids = HashMap::new();
+    for (new_id, (rider_id, orders)) in plan.iter().sorted_by_key(|(id, _)| **id).enumerate() {
+        dense.insert(new_id as u32, orders.clone());
+        original_ids.insert(new_id as u32, *rider_id);
+    }
+    (dense, original_ids)
+}
+
+// Whether the plans match once riders are relabeled, i.e. some bijection
+// between their rider ids maps every queue to one with the same orders
+#[must_use]
+fn isomorphic(a: &Plan, b: &Plan) -> bool {
+    let queues = |plan: &Plan| -> Vec<Vec<u64>> {
+        plan.values()
+            .map(|orders| orders.iter().cloned().sorted().collect())
+            .sorted()
+            .collect()
+    };
+    queues(a) == queues(b)
+}
+
+// splitmix64's output function, spreads every input bit over the result
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Fingerprint of who holds which order. Pairs are hashed one by one and
+// summed, so queue order, empty riders and map order don't matter and
+// plans_equivalent plans always agree.
+#[must_use]
+fn plan_checksum(plan: &Plan) -> u64 {
+    plan.iter()
+        .flat_map(|(rider_id, orders)| orders.iter().map(move |order_id| (*rider_id, *order_id)))
+        .map(|(rider_id, order_id)| mix64(mix64(order_id) ^ rider_id as u64))
+        .fold(0, u64::wrapping_add)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PlanChange {
+    Moved { order_id: u64, from: u32, to: u32 },
+}
+
+// Difference between the most and the least loaded rider
+#[must_use]
+fn load_spread(plan: &Plan) -> usize {
+    plan.values()
+        .map(|orders| orders.len())
+        .minmax()
+        .into_option()
+        .map_or(0, |(min, max)| max - min)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct PlanMetrics {
+    riders: usize,
+    total_orders: usize,
+    load_spread: usize,
+}
+
+#[must_use]
+fn plan_metrics(plan: &Plan) -> PlanMetrics {
+    PlanMetrics {
+        riders: plan.len(),
+        total_orders: plan.values().map(|orders| orders.len()).sum(),
+        load_spread: load_spread(plan),
+    }
+}
+
+// How evenly orders are spread over the plan's riders
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FairnessReport {
+    // 0 when every rider has the same load, up to 1 - 1/riders when one rider
+    // has everything
+    gini: f64,
+    // Standard deviation of the loads over their mean, 0 when balanced
+    coefficient_of_variation: f64,
+    // Busiest over idlest load: 1 when balanced, infinite when some rider has
+    // nothing while another has orders
+    max_min_ratio: f64,
+}
+
+// A plan without riders or orders counts as perfectly fair
+#[must_use]
+fn fairness_report(plan: &Plan) -> FairnessReport {
+    let loads: Vec<f64> = plan.values().map(|orders| orders.len() as f64).collect();
+    let n = loads.len() as f64;
+    let mean = loads.iter().sum::<f64>() / n;
+    if loads.is_empty() || mean == 0.0 {
+        return FairnessReport {
+            gini: 0.0,
+            coefficient_of_variation: 0.0,
+            max_min_ratio: 1.0,
+        };
+    }
+    let pairwise: f64 = loads
+        .iter()
+        .flat_map(|a| loads.iter().map(move |b| (a - b).abs()))
+        .sum();
+    let variance = loads.iter().map(|load| (load - mean).powi(2)).sum::<f64>() / n;
+    let max = loads.iter().cloned().fold(0.0, f64::max);
+    let min = loads.iter().cloned().fold(f64::INFINITY, f64::min);
+    FairnessReport {
+        gini: pairwise / (2.0 * n * n * mean),
+        coefficient_of_variation: variance.sqrt() / mean,
+        max_min_ratio: max / min,
+    }
+}
+
+// Standard deviation of the per-rider loads, lower is better balanced
+#[must_use]
+fn assignment_quality(plan: &Plan) -> f64 {
+    let loads: Vec<f64> = plan.values().map(|orders| orders.len() as f64).collect();
+    if loads.is_empty() {
+        return 0.0;
+    }
+    let mean = loads.iter().sum::<f64>() / loads.len() as f64;
+    (loads.iter().map(|load| (load - mean).powi(2)).sum::<f64>() / loads.len() as f64).sqrt()
+}
+
+// Gauges in the Prometheus text exposition format, riders by ascending id
+fn plan_metrics_prometheus(plan: &Plan) -> String {
+    let metrics = plan_metrics(plan);
+    let mut out = String::from("# TYPE dispatch_rider_orders gauge\n");
+    for (rider_id, orders) in manifest(plan) {
+        out += &format!(
+            "dispatch_rider_orders{{rider=\"{rider_id}\"}} {}\n",
+            orders.len()
+        );
+    }
+    out += &format!(
+        "# TYPE dispatch_total_orders gauge\ndispatch_total_orders {}\n",
+        metrics.total_orders
+    );
+    out += &format!(
+        "# TYPE dispatch_load_spread gauge\ndispatch_load_spread {}\n",
+        metrics.load_spread
+    );
+    out
+}
+
+// Riders of `capacity` it takes to carry `order_count` orders, rounded up.
+// None on a zero capacity, since no number of such riders will do.
+#[must_use]
+fn riders_needed(order_count: usize, capacity: u32) -> Option<usize> {
+    (capacity > 0).then(|| order_count.div_ceil(capacity as usize))
+}
+
+// Riders to add (positive) or let go (negative) so the forecast fits, given
+// the plan's current rider count. None on a zero capacity, as riders_needed.
+#[must_use]
+fn recommend_scaling(plan: &Plan, forecast_orders: usize, capacity: u32) -> Option<i64> {
+    riders_needed(forecast_orders, capacity).map(|needed| needed as i64 - plan.len() as i64)
+}
+
+// Moves orders from the tail of the busiest rider's queue to the least busy
+// rider until the spread is at most one. Ties pick the lowest rider id, so the
+// result only depends on the plan's contents. That determinism is also what
+// makes a rebalanced plan a fixpoint: running it again moves nothing.
+fn rebalance(plan: &mut Plan) -> Vec<PlanChange> {
+    let mut changes = vec![];
+    loop {
+        let busiest = plan
+            .iter()
+            .max_by_key(|(id, orders)| (orders.len(), std::cmp::Reverse(**id)));
+        let idlest = plan.iter().min_by_key(|(id, orders)| (orders.len(), **id));
+        let (from, to) = match (busiest, idlest) {
+            (Some((from, busy)), Some((to, idle))) if busy.len() > idle.len() + 1 => (*from, *to),
+            _ => return changes,
+        };
+        let order_id = plan.get_mut(&from).and_then(|orders| orders.pop()).unwrap();
+        plan.entry(to).or_default().push(order_id);
+        changes.push(PlanChange::Moved { order_id, from, to });
+    }
+}
+
+// Moves orders off riders above their weighted share onto riders below it,
+// lowest ids first and always from the tail of the queue. Shares are
+// `total * weight / sum` rounded by largest remainder (ties to the lowest id),
+// so each rider ends within one order of its exact share. Riders without a
+// weight get none. A zero weight sum leaves the plan alone.
+fn rebalance_to_weights(plan: &mut Plan, weights: &HashMap<u32, u32>) -> Vec<PlanChange> {
+    let sum: u128 = weights.values().map(|w| *w as u128).sum();
+    if sum == 0 {
+        return vec![];
+    }
+    let total = plan.values().map(|orders| orders.len()).sum::<usize>() as u128;
+    let mut targets: HashMap<u32, usize> = plan.keys().map(|rider_id| (*rider_id, 0)).collect();
+    let mut handed_out = 0;
+    for (rider_id, weight) in weights {
+        let share = total * *weight as u128 / sum;
+        targets.insert(*rider_id, share as usize);
+        handed_out += share;
+    }
+    let by_remainder = weights
+        .iter()
+        .map(|(rider_id, weight)| (total * *weight as u128 % sum, *rider_id))
+        .sorted_by_key(|(remainder, rider_id)| (std::cmp::Reverse(*remainder), *rider_id));
+    for (_, rider_id) in by_remainder.take((total - handed_out) as usize) {
+        *targets.get_mut(&rider_id).unwrap() += 1;
+    }
+
+    let load = |plan: &Plan, rider_id: u32| plan.get(&rider_id).map_or(0, |orders| orders.len());
+    let (mut over, mut under): (Vec<u32>, Vec<u32>) = (vec![], vec![]);
+    for rider_id in targets.keys().cloned().sorted() {
+        match load(plan, rider_id).cmp(&targets[&rider_id]) {
+            std::cmp::Ordering::Greater => over.push(rider_id),
+            std::cmp::Ordering::Less => under.push(rider_id),
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+    // Both sides are off by the same total, so they run out together
+    let mut changes = vec![];
+    let mut under = under.into_iter();
+    let mut to = under.next();
+    for from in over {
+        while load(plan, from) > targets[&from] {
+            let receiver = to.unwrap();
+            let order_id = plan.get_mut(&from).and_then(|orders| orders.pop()).unwrap();
+            plan.entry(receiver).or_default().push(order_id);
+            changes.push(PlanChange::Moved {
+                order_id,
+                from,
+                to: receiver,
+            });
+            if load(plan, receiver) == targets[&receiver] {
+                to = under.next();
+            }
+        }
+    }
+    changes
+}
+
+// Replays changes returned by rebalance or rebalance_to_weights, moving each
+// order to the back of the receiving rider's queue
+fn apply_changes(plan: &mut Plan, changes: &[PlanChange]) {
+    for change in changes {
+        match *change {
+            PlanChange::Moved { order_id, from, to } => {
+                if let Some(orders) = plan.get_mut(&from) {
+                    if let Some(idx) = orders.iter().rposition(|v| *v == order_id) {
+                        orders.remove(idx);
+                    }
+                }
+                plan.entry(to).or_default().push(order_id);
+            }
+        }
+    }
+}
+
+// The moves that take `before` to `after`, by ascending order id. An order
+// held by several riders counts as being with the lowest id; orders in only
+// one of the plans aren't moves and are left out.
+#[must_use]
+fn diff_plans(before: &Plan, after: &Plan) -> Vec<PlanChange> {
+    let now = order_holders(after);
+    order_holders(before)
+        .into_iter()
+        .sorted()
+        .filter_map(|(order_id, riders)| {
+            let from = *riders.first()?;
+            let to = *now.get(&order_id)?.first()?;
+            (from != to).then_some(PlanChange::Moved { order_id, from, to })
+        })
+        .collect()
+}
+
+// Maps ids of any type to dense u32 handles, so a plan over large ids (say
+// strings from an external system) stores each distinct id once
+#[derive(Clone, Debug)]
+struct Interner<O> {
+    ids: Vec<O>,
+    handles: HashMap<O, u32>,
+}
+
+impl<O> Default for Interner<O> {
+    fn default() -> Self {
+        Interner {
+            ids: Vec::new(),
+            handles: HashMap::new(),
+        }
+    }
+}
+
+impl<O: Clone + Eq + std::hash::Hash> Interner<O> {
+    // The id's handle, allocating the next one on first sight
+    fn intern(&mut self, id: O) -> u32 {
+        if let Some(handle) = self.handles.get(&id) {
+            return *handle;
+        }
+        let handle = self.ids.len() as u32;
+        self.ids.push(id.clone());
+        self.handles.insert(id, handle);
+        handle
+    }
+
+    // None for a handle this interner never gave out
+    fn resolve(&self, handle: u32) -> Option<&O> {
+        self.ids.get(handle as usize)
+    }
+}
+
+// A plan over arbitrary order ids, keeping only handles in the queues
+#[derive(Clone, Debug)]
+struct InternedPlan<O> {
+    orders: Interner<O>,
+    plan: PlanMap<u32, Vec<u32>>,
+}
+
+impl<O> Default for InternedPlan<O> {
+    fn default() -> Self {
+        InternedPlan {
+            orders: Interner::default(),
+            plan: PlanMap::default(),
+        }
+    }
+}
+
+impl<O: Clone + Eq + std::hash::Hash> InternedPlan<O> {
+    fn assign(&mut self, rider_id: u32, order_id: O) {
+        let handle = self.orders.intern(order_id);
+        self.plan.entry(rider_id).or_default().push(handle);
+    }
+
+    // The rider's queue with the original ids
+    fn orders_of(&self, rider_id: u32) -> Vec<&O> {
+        self.plan.get(&rider_id).map_or(vec![], |handles| {
+            handles
+                .iter()
+                .filter_map(|h| self.orders.resolve(*h))
+                .collect()
+        })
+    }
+}
+
+// Binary plan encoding: magic, version, then riders sorted by id, each as
+// varint id, varint order count and varint order ids
+#[cfg(feature = "bincode")]
+const PLAN_MAGIC: &[u8; 4] = b"PLAN";
+#[cfg(feature = "bincode")]
+const PLAN_FORMAT_VERSION: u8 = 1;
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    VarintOverflow,
+    TrailingBytes,
+    // A rider id that isn't above the one before it, so repeated or out of order
+    DuplicateRider(u32),
+}
+
+#[cfg(feature = "bincode")]
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(feature = "bincode")]
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or(DecodeError::Truncated)?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DecodeError::VarintOverflow)
+}
+
+#[cfg(feature = "bincode")]
+#[must_use]
+fn plan_to_bytes(plan: &Plan) -> Vec<u8> {
+    let mut out = PLAN_MAGIC.to_vec();
+    out.push(PLAN_FORMAT_VERSION);
+    write_varint(&mut out, plan.len() as u64);
+    for (rider_id, orders) in plan.iter().sorted_by_key(|(rider_id, _)| **rider_id) {
+        write_varint(&mut out, *rider_id as u64);
+        write_varint(&mut out, orders.len() as u64);
+        for order_id in orders {
+            write_varint(&mut out, *order_id);
+        }
+    }
+    out
+}
+
+#[cfg(feature = "bincode")]
+fn plan_from_bytes(mut b: &[u8]) -> Result<Plan, DecodeError> {
+    if b.len() < PLAN_MAGIC.len() + 1 {
+        return Err(DecodeError::Truncated);
+    }
+    let after_magic = b
+        .strip_prefix(PLAN_MAGIC.as_slice())
+        .ok_or(DecodeError::BadMagic)?;
+    let (&version, rest) = after_magic.split_first().ok_or(DecodeError::Truncated)?;
+    if version != PLAN_FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    b = rest;
+
+    let mut plan = Plan::default();
+    let mut prev_rider_id = None;
+    let riders = read_varint(&mut b)?;
+    for _ in 0..riders {
+        let rider_id =
+            u32::try_from(read_varint(&mut b)?).map_err(|_| DecodeError::VarintOverflow)?;
+        if prev_rider_id.is_some_and(|prev| rider_id <= prev) {
+            return Err(DecodeError::DuplicateRider(rider_id));
+        }
+        prev_rider_id = Some(rider_id);
+        let count = read_varint(&mut b)?;
+        // Don't trust the count for preallocation, a corrupt one could be huge
+        let mut orders = Vec::new();
+        for _ in 0..count {
+            orders.push(read_varint(&mut b)?);
+        }
+        plan.insert(rider_id, orders);
+    }
+    if !b.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(plan)
+}
+
+// A plan as JSON, an object from rider id to its order queue with riders by
+// ascending id: {"1":[10,11],"2":[]}. Files, stores, scenarios and the HTTP
+// handlers all use this form.
+#[must_use]
+fn plan_to_json(plan: &Plan) -> json::Json {
+    json::Json::Object(
+        manifest(plan)
+            .into_iter()
+            .map(|(rider_id, orders)| {
+                let orders = orders.into_iter().map(json::Json::from).collect();
+                (rider_id.to_string(), json::Json::Array(orders))
+            })
+            .collect(),
+    )
+}
+
+// None when the JSON isn't shaped like plan_to_json output
+#[must_use]
+fn plan_from_json(json: &json::Json) -> Option<Plan> {
+    json.as_object()?
+        .iter()
+        .map(|(rider_id, orders)| {
+            let orders = orders
+                .as_array()?
+                .iter()
+                .map(json::Json::as_u64)
+                .collect::<Option<Vec<_>>>()?;
+            Some((rider_id.parse().ok()?, orders))
+        })
+        .collect()
+}
+
+// A file that isn't a plan fails to load with InvalidData
+fn save_plan(plan: &Plan, path: &std::path::Path) -> std::io::Result<()> {
+    std::fs::write(path, plan_to_json(plan).to_string())
+}
+
+fn load_plan(path: &std::path::Path) -> std::io::Result<Plan> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let json =
+        json::Json::parse(&std::fs::read_to_string(path)?).map_err(|e| invalid(e.to_string()))?;
+    plan_from_json(&json).ok_or_else(|| invalid("not a plan".to_string()))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum StoreError {
+    Io(std::io::ErrorKind),
+    // Something is stored under the key but it isn't a plan
+    Corrupt { key: String },
+    // The key can't name a file in the store, e.g. it's empty or has a path separator
+    InvalidKey { key: String },
+}
+
+// Somewhere plans can be kept by key. Plans are stored in the JSON form of
+// save_plan whatever the backend.
+trait PlanStore {
+    fn save(&self, key: &str, plan: &Plan) -> Result<(), StoreError>;
+    // Ok(None) when nothing was saved under `key`
+    fn load(&self, key: &str) -> Result<Option<Plan>, StoreError>;
+}
+
+// One `<key>.json` file per plan in `dir`
+struct FileStore {
+    dir: std::path::PathBuf,
+}
+
+impl FileStore {
+    // Keys that could reach outside `dir` are refused
+    fn path(&self, key: &str) -> Result<std::path::PathBuf, StoreError> {
+        if key.is_empty() || key.contains(['/', '\\']) || key.contains("..") {
+            return Err(StoreError::InvalidKey {
+                key: key.to_string(),
+            });
+        }
+        Ok(self.dir.join(format!("{key}.json")))
+    }
+}
+
+impl PlanStore for FileStore {
+    fn save(&self, key: &str, plan: &Plan) -> Result<(), StoreError> {
+        save_plan(plan, &self.path(key)?).map_err(|e| StoreError::Io(e.kind()))
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Plan>, StoreError> {
+        match load_plan(&self.path(key)?) {
+            Ok(plan) => Ok(Some(plan)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(StoreError::Corrupt {
+                key: key.to_string(),
+            }),
+            Err(e) => Err(StoreError::Io(e.kind())),
+        }
+    }
+}
+
+// Encoded plans in memory, for tests and single-process setups
+#[derive(Default)]
+struct MemStore(RwLock<HashMap<String, String>>);
+
+impl PlanStore for MemStore {
+    fn save(&self, key: &str, plan: &Plan) -> Result<(), StoreError> {
+        let encoded = plan_to_json(plan).to_string();
+        self.0
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key.to_string(), encoded);
+        Ok(())
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Plan>, StoreError> {
+        let stored = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(encoded) = stored.get(key) else {
+            return Ok(None);
+        };
+        json::Json::parse(encoded)
+            .ok()
+            .and_then(|json| plan_from_json(&json))
+            .map(Some)
+            .ok_or_else(|| StoreError::Corrupt {
+                key: key.to_string(),
+            })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PlanViolation {
+    // The order is queued more than once, riders lists every holder (with repeats)
+    DuplicateOrder { order_id: u64, riders: Vec<u32> },
+    EmptyRider { rider_id: u32 },
+}
+
+// Whether riders without orders are kept in the plan or dropped from it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EmptyRiderPolicy {
+    Keep,
+    Prune,
+}
+
+#[must_use]
+fn order_holders(plan: &Plan) -> HashMap<u64, Vec<u32>> {
+    let mut holders: HashMap<u64, Vec<u32>> = HashMap::new();
+    for (rider_id, orders) in plan.iter().sorted_by_key(|(rider_id, _)| **rider_id) {
+        for order_id in orders {
+            holders.entry(*order_id).or_default().push(*rider_id);
+        }
+    }
+    holders
+}
+
+// Reports the duplicated order with the lowest id, if any
+fn validate_plan(plan: &Plan) -> Result<(), PlanViolation> {
+    match order_holders(plan)
+        .into_iter()
+        .filter(|(_, riders)| riders.len() > 1)
+        .min_by_key(|(order_id, _)| *order_id)
+    {
+        Some((order_id, riders)) => Err(PlanViolation::DuplicateOrder { order_id, riders }),
+        None => Ok(()),
+    }
+}
+
+fn repair_plan(plan: &mut Plan) -> Vec<PlanViolation> {
+    repair_plan_with(plan, EmptyRiderPolicy::Keep)
+}
+
+// Checks only what the event behind `last_outcome` could have broken, assuming
+// the plan was valid before it. Cheap enough to run after every event.
+fn validate_delta(plan: &Plan, last_outcome: &EventOutcome) -> Result<(), PlanViolation> {
+    let check_move = |order_id: u64, from: u32, to: u32| {
+        let on = |rider_id: u32| {
+            plan.get(&rider_id).map_or(0, |orders| {
+                orders.iter().filter(|v| **v == order_id).count()
+            })
+        };
+        let (on_from, on_to) = (on(from), if from == to { 0 } else { on(to) });
+        if on_from + on_to > 1 {
+            let riders = std::iter::repeat_n(from, on_from)
+                .chain(std::iter::repeat_n(to, on_to))
+                .sorted()
+                .collect();
+            return Err(PlanViolation::DuplicateOrder { order_id, riders });
+        }
+        Ok(())
+    };
+    match last_outcome {
+        EventOutcome::Reassigned { order_id, from, to } => check_move(*order_id, *from, *to),
+        EventOutcome::Assigned { order_id, rider_id } => {
+            check_move(*order_id, *rider_id, *rider_id)
+        }
+        EventOutcome::RiderAdded { moves, .. } => {
+            moves.iter().try_for_each(|change| match change {
+                PlanChange::Moved { order_id, from, to } => check_move(*order_id, *from, *to),
+            })
+        }
+        // Removing orders can't duplicate anything
+        EventOutcome::Canceled { .. }
+        | EventOutcome::Held { .. }
+        | EventOutcome::Ignored { .. } => Ok(()),
+    }
+}
+
+// Keeps each duplicated order only on the lowest-id rider (first position when
+// a rider holds it twice) and returns everything that was fixed
+fn repair_plan_with(plan: &mut Plan, policy: EmptyRiderPolicy) -> Vec<PlanViolation> {
+    let mut fixed: Vec<PlanViolation> = order_holders(plan)
+        .into_iter()
+        .filter(|(_, riders)| riders.len() > 1)
+        .sorted_by_key(|(order_id, _)| *order_id)
+        .map(|(order_id, riders)| PlanViolation::DuplicateOrder { order_id, riders })
+        .collect();
+
+    let mut seen = HashSet::new();
+    for rider_id in plan.keys().cloned().sorted().collect::<Vec<_>>() {
+        if let Some(orders) = plan.get_mut(&rider_id) {
+            orders.retain(|order_id| seen.insert(*order_id));
+        }
+    }
+
+    if policy == EmptyRiderPolicy::Prune {
+        let empty: Vec<u32> = plan
+            .iter()
+            .filter(|(_, orders)| orders.is_empty())
+            .map(|(rider_id, _)| *rider_id)
+            .sorted()
+            .collect();
+        for rider_id in empty {
+            plan.remove(&rider_id);
+            fixed.push(PlanViolation::EmptyRider { rider_id });
+        }
+    }
+    fixed
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PlanError {
+    UnknownRider { id: u32 },
+    DuplicateOrder { order_id: u64 },
+}
+
+// Builds a plan from (rider id, order id) pairs, queues in the given order.
+// Riders without assignments are left out. Fails on the first pair naming a
+// rider missing from the roster or an order that was already assigned.
+fn plan_from_assignments(riders: &[Rider], assignments: &[(u32, u64)]) -> Result<Plan, PlanError> {
+    let roster: HashSet<u32> = riders.iter().map(|r| r.id).collect();
+    let mut seen = HashSet::new();
+    let mut plan = Plan::default();
+    for (rider_id, order_id) in assignments {
+        if !roster.contains(rider_id) {
+            return Err(PlanError::UnknownRider { id: *rider_id });
+        }
+        if !seen.insert(*order_id) {
+            return Err(PlanError::DuplicateOrder {
+                order_id: *order_id,
+            });
+        }
+        plan.entry(*rider_id).or_default().push(*order_id);
+    }
+    Ok(plan)
+}
+
+// Orders queued on riders missing from the roster, by ascending rider id and
+// then queue order
+#[must_use]
+fn orphaned_orders(plan: &Plan, current_riders: &[Rider]) -> Vec<u64> {
+    let roster: HashSet<u32> = current_riders.iter().map(|r| r.id).collect();
+    manifest(plan)
+        .into_iter()
+        .filter(|(rider_id, _)| !roster.contains(rider_id))
+        .flat_map(|(_, orders)| orders)
+        .collect()
+}
+
+// Drops riders missing from the roster and hands their orders to the least
+// loaded current riders, returning the moved ids. Capacity is not checked,
+// the orders were already promised. With an empty roster nothing moves.
+fn reclaim_orphans(plan: &mut Plan, current_riders: &[Rider]) -> Vec<u64> {
+    if current_riders.is_empty() {
+        return vec![];
+    }
+    let orphans = orphaned_orders(plan, current_riders);
+    let roster: HashSet<u32> = current_riders.iter().map(|r| r.id).collect();
+    plan.retain(|rider_id, _| roster.contains(rider_id));
+    for rider in current_riders {
+        plan.entry(rider.id).or_default();
+    }
+    for order_id in &orphans {
+        assign_one(plan, *order_id);
+    }
+    orphans
+}
+
+// The plan's riders by ascending id, ready to take a new day's orders. The
+// plan does not record capacities, so every rider comes back unlimited.
+#[must_use]
+fn template_from(plan: &Plan) -> Vec<Rider> {
+    plan.keys()
+        .sorted()
+        .map(|id| Rider {
+            id: *id,
+            ..Default::default()
+        })
+        .collect()
+}
+
+// Round-robin over `riders`, keeping an empty queue for each rider left
+// without orders. With no riders nothing is assigned.
+#[must_use]
+fn reassign_fresh(riders: &[Rider], orders: &[Order]) -> Plan {
+    let mut plan: Plan = riders.iter().map(|r| (r.id, vec![])).collect();
+    if !riders.is_empty() {
+        plan.extend(compute_plan(riders, orders));
+    }
+    plan
+}
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// Great-circle distance between two (latitude, longitude) points in degrees
+#[must_use]
+fn haversine_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat_a, lat_b) = (a.0.to_radians(), b.0.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.1 - a.1).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().min(1.0).asin()
+}
+
+// Sum of rider-to-order distances times the rate. Assignments where the rider
+// or the order has no known location are not billed.
+#[must_use]
+fn plan_cost(plan: &Plan, riders: &[Rider], orders: &[Order], rate_per_km: f64) -> f64 {
+    let rider_locations: HashMap<u32, (f64, f64)> = riders
+        .iter()
+        .filter_map(|r| r.location.map(|loc| (r.id, loc)))
+        .collect();
+    let order_locations: HashMap<u64, (f64, f64)> = orders
+        .iter()
+        .filter_map(|o| o.location.map(|loc| (o.id, loc)))
+        .collect();
+    plan.iter()
+        .filter_map(|(rider_id, assigned)| Some((rider_locations.get(rider_id)?, assigned)))
+        .flat_map(|(from, assigned)| {
+            assigned
+                .iter()
+                .filter_map(|order_id| order_locations.get(order_id))
+                .map(move |to| haversine_km(*from, *to))
+        })
+        .sum::<f64>()
+        * rate_per_km
+}
+
+// Distance scaled by a traffic factor, 1.0 for free-flowing roads
+#[must_use]
+fn travel_cost(a: (f64, f64), b: (f64, f64), traffic: f64) -> f64 {
+    haversine_km(a, b) * traffic
+}
+
+// Each order goes to the closest rider with a location, regardless of load,
+// among those whose radius reaches it. Orders without a location, or out of
+// every rider's reach, are returned as leftovers.
+#[must_use]
+fn compute_plan_nearest(riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+    compute_plan_nearest_by(riders, orders, |_, _| 1.0)
+}
+
+// compute_plan_nearest by travel cost, with `traffic_fn(rider, order)` giving
+// the factor for the trip between the two locations
+#[must_use]
+fn compute_plan_nearest_by(
+    riders: &[Rider],
+    orders: &[Order],
+    traffic_fn: impl Fn((f64, f64), (f64, f64)) -> f64,
+) -> (Plan, Vec<u64>) {
+    let mut plan = Plan::default();
+    let mut leftovers = Vec::new();
+    for order in orders {
+        let nearest = order.location.and_then(|to| {
+            riders
+                .iter()
+                .filter(|r| r.within_radius(to))
+                .filter_map(|r| {
+                    let from = r.location?;
+                    Some((travel_cost(from, to, traffic_fn(from, to)), r.id))
+                })
+                .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        match nearest {
+            Some((_, rider_id)) => plan.entry(rider_id).or_default().push(order.id),
+            None => leftovers.push(order.id),
+        }
+    }
+    (plan, leftovers)
+}
+
+// The candidate that would cut the most travel off `plan`, a
+// compute_plan_nearest plan over `riders`, if they joined and the orders were
+// planned by nearest again. Ties go to the lowest id. None when no candidate
+// makes the total any shorter.
+#[must_use]
+fn best_rider_to_add(
+    plan: &Plan,
+    riders: &[Rider],
+    orders: &[Order],
+    candidates: &[Rider],
+) -> Option<u32> {
+    let current = plan_cost(plan, riders, orders, 1.0);
+    candidates
+        .iter()
+        .map(|candidate| {
+            let mut staffed = riders.to_vec();
+            staffed.push(candidate.clone());
+            let (replanned, _) = compute_plan_nearest(&staffed, orders);
+            (
+                current - plan_cost(&replanned, &staffed, orders, 1.0),
+                candidate.id,
+            )
+        })
+        .filter(|(saved, _)| *saved > 0.0)
+        .max_by(|(a, a_id), (b, b_id)| a.total_cmp(b).then(b_id.cmp(a_id)))
+        .map(|(_, id)| id)
+}
+
+// compute_plan_nearest plus each rider's summed distance to its orders, for
+// route-length reports. Only riders that got orders are listed; orders left
+// over by compute_plan_nearest are dropped.
+#[must_use]
+fn compute_plan_nearest_with_distances(
+    riders: &[Rider],
+    orders: &[Order],
+) -> (Plan, HashMap<u32, f64>) {
+    let (plan, _) = compute_plan_nearest(riders, orders);
+    let rider_locations: HashMap<u32, (f64, f64)> = riders
+        .iter()
+        .filter_map(|r| r.location.map(|loc| (r.id, loc)))
+        .collect();
+    let order_locations: HashMap<u64, (f64, f64)> = orders
+        .iter()
+        .filter_map(|o| o.location.map(|loc| (o.id, loc)))
+        .collect();
+    let distances = plan
+        .iter()
+        .filter_map(|(rider_id, assigned)| {
+            let from = *rider_locations.get(rider_id)?;
+            let total = assigned
+                .iter()
+                .filter_map(|order_id| order_locations.get(order_id))
+                .map(|to| haversine_km(from, *to))
+                .sum();
+            Some((*rider_id, total))
+        })
+        .collect();
+    (plan, distances)
+}
+
+// Reorders the queue as a nearest-neighbor tour from the rider's location:
+// each stop is the closest remaining order, earlier in the queue on ties.
+// Without a rider location the tour starts at the first located order. Orders
+// without coordinates keep their relative order at the end.
+fn optimize_queue(rider: &Rider, order_coords: &HashMap<u64, (f64, f64)>, queue: &mut Vec<u64>) {
+    let (mut remaining, unlocated): (Vec<u64>, Vec<u64>) = queue
+        .iter()
+        .partition(|order_id| order_coords.contains_key(order_id));
+    let mut tour = Vec::with_capacity(queue.len());
+    let mut at = rider.location.or_else(|| {
+        remaining
+            .first()
+            .and_then(|id| order_coords.get(id).copied())
+    });
+    while let Some(from) = at {
+        let Some(idx) = remaining
+            .iter()
+            .map(|id| {
+                order_coords
+                    .get(id)
+                    .map_or(f64::INFINITY, |to| haversine_km(from, *to))
+            })
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(idx, _)| idx)
+        else {
+            break;
+        };
+        let next = remaining.remove(idx);
+        at = order_coords.get(&next).copied();
+        tour.push(next);
+    }
+    tour.extend(unlocated);
+    *queue = tour;
+}
+
+// How much each criterion counts in compute_plan_scored
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ScoreWeights {
+    distance: f64,
+    load: f64,
+    rating: f64,
+}
+
+// Each order goes to the rider with the lowest weighted score, lowest id on
+// ties. Distance and load are divided by their largest value over the riders
+// so both run from 0 to 1; the rating term is 1 / (1 + rating). A rider or
+// order without a location counts as the farthest. Capacity is not checked.
+#[must_use]
+fn compute_plan_scored(riders: &[Rider], orders: &[Order], weights: &ScoreWeights) -> Plan {
+    let mut plan = Plan::default();
+    for order in orders {
+        let distances: Vec<Option<f64>> = riders
+            .iter()
+            .map(|r| Some(haversine_km(r.location?, order.location?)))
+            .collect();
+        let max_distance = distances.iter().flatten().cloned().fold(0.0, f64::max);
+        let loads: Vec<usize> = riders
+            .iter()
+            .map(|r| plan.get(&r.id).map_or(0, |o| o.len()))
+            .collect();
+        let max_load = loads.iter().cloned().max().unwrap_or(0);
+        let ratio = |value: f64, max: f64| if max > 0.0 { value / max } else { 0.0 };
+        let best = riders
+            .iter()
+            .zip(&distances)
+            .zip(&loads)
+            .map(|((r, distance), load)| {
+                let distance = distance.map_or(1.0, |d| ratio(d, max_distance));
+                let load = ratio(*load as f64, max_load as f64);
+                let score = weights.distance * distance
+                    + weights.load * load
+                    + weights.rating / (1.0 + r.rating);
+                (score, r.id)
+            })
+            .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        if let Some((_, rider_id)) = best {
+            plan.entry(rider_id).or_default().push(order.id);
+        }
+    }
+    plan
+}
+
+// Each order goes to the rider with the fewest orders that still has capacity,
+// ties going to the lowest rider id
+#[must_use]
+fn compute_plan_least_loaded(riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+    let mut plan = Plan::default();
+    let mut leftovers = Vec::new();
+    for order in orders {
+        let least_loaded = riders
+            .iter()
+            .map(|r| (plan.get(&r.id).map_or(0, |o| o.len()), r))
+            .filter(|(load, r)| r.has_room(*load))
+            .min_by_key(|(load, r)| (*load, r.id));
+        match least_loaded {
+            Some((_, rider)) => plan.entry(rider.id).or_default().push(order.id),
+            None => leftovers.push(order.id),
+        }
+    }
+    (plan, leftovers)
+}
+
+// Per-rider sum of `amount` over the queued orders, for every rider of the
+// plan. Orders missing from `orders` count as zero.
+#[must_use]
+fn totals_by(plan: &Plan, orders: &[Order], amount: fn(&Order) -> u32) -> HashMap<u32, u32> {
+    let amounts: HashMap<u64, u32> = orders.iter().map(|o| (o.id, amount(o))).collect();
+    plan.iter()
+        .map(|(rider_id, queue)| {
+            let total = queue
+                .iter()
+                .map(|order_id| amounts.get(order_id).cloned().unwrap_or(0))
+                .fold(0u32, u32::saturating_add);
+            (*rider_id, total)
+        })
+        .collect()
+}
+
+// Each order goes to the rider with the lowest `amount` total so far, ties
+// going to the lowest rider id. Capacity is not checked and riders left
+// without orders are not in the plan, as with compute_plan.
+#[must_use]
+fn compute_plan_least_by(riders: &[Rider], orders: &[Order], amount: fn(&Order) -> u32) -> Plan {
+    let mut plan = Plan::default();
+    let mut totals: HashMap<u32, u32> = HashMap::new();
+    for order in orders {
+        let Some(rider) = riders
+            .iter()
+            .min_by_key(|r| (totals.get(&r.id).cloned().unwrap_or(0), r.id))
+        else {
+            break;
+        };
+        plan.entry(rider.id).or_default().push(order.id);
+        let total = totals.entry(rider.id).or_default();
+        *total = total.saturating_add(amount(order));
+    }
+    plan
+}
+
+// Total service minutes queued on every rider of the plan
+#[must_use]
+fn projected_time(plan: &Plan, orders: &[Order]) -> HashMap<u32, u32> {
+    totals_by(plan, orders, |o| o.service_minutes)
+}
+
+// Balances by service minutes instead of order count
+#[must_use]
+fn compute_plan_by_time(riders: &[Rider], orders: &[Order]) -> Plan {
+    compute_plan_least_by(riders, orders, |o| o.service_minutes)
+}
+
+// Total order value queued on every rider of the plan
+#[must_use]
+fn rider_values(plan: &Plan, orders: &[Order]) -> HashMap<u32, u32> {
+    totals_by(plan, orders, |o| o.value)
+}
+
+// Balances by order value, for spreading revenue rather than work
+#[must_use]
+fn compute_plan_by_value(riders: &[Rider], orders: &[Order]) -> Plan {
+    compute_plan_least_by(riders, orders, |o| o.value)
+}
+
+// A plan too uneven to dispatch
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SlaViolation {
+    actual_spread: usize,
+}
+
+// compute_plan_least_loaded, refused when the busiest and the idlest rider
+// differ by more than `max_spread` orders. Riders left without orders count
+// as idle. Orders nobody has room for are left out, is_feasible catches
+// those up front.
+fn compute_plan_sla(
+    riders: &[Rider],
+    orders: &[Order],
+    max_spread: usize,
+) -> Result<Plan, SlaViolation> {
+    let (plan, _) = compute_plan_least_loaded(riders, orders);
+    let actual_spread = riders
+        .iter()
+        .map(|r| plan.get(&r.id).map_or(0, |queue| queue.len()))
+        .minmax()
+        .into_option()
+        .map_or(0, |(min, max)| max - min);
+    if actual_spread > max_spread {
+        return Err(SlaViolation { actual_spread });
+    }
+    Ok(plan)
+}
+
+// Re-plans one zone: the queues of `zone`'s riders are replaced with a fresh
+// round-robin of `zone`'s orders, everyone else's are left as they are. Zone
+// orders already queued on a rider from another zone stay with that rider.
+fn recompute_zone(plan: &mut Plan, riders: &[Rider], orders: &[Order], zone: u16) {
+    let zone_riders: Vec<Rider> = riders.iter().filter(|r| r.zone == zone).cloned().collect();
+    let elsewhere: HashSet<u64> = plan
+        .iter()
+        .filter(|(rider_id, _)| !zone_riders.iter().any(|r| r.id == **rider_id))
+        .flat_map(|(_, queue)| queue.iter().cloned())
+        .collect();
+    let zone_orders: Vec<Order> = orders
+        .iter()
+        .filter(|o| o.zone == zone && !elsewhere.contains(&o.id))
+        .cloned()
+        .collect();
+    let fresh = compute_plan(&zone_riders, &zone_orders);
+    for rider in &zone_riders {
+        plan.insert(rider.id, fresh.get(&rider.id).cloned().unwrap_or_default());
+    }
+}
+
+// Groups orders into at most `k` clusters (a zero `k` counts as one) by
+// k-means over raw (latitude, longitude), which is close enough within a city.
+// The first `k` located orders seed the centroids, so the result only depends
+// on the input. Clusters come in seed order with orders in input order, empty
+// ones dropped; orders without a location share a last cluster of their own.
+#[must_use]
+fn cluster_orders(orders: &[Order], k: usize) -> Vec<Vec<u64>> {
+    let located: Vec<(u64, (f64, f64))> = orders
+        .iter()
+        .filter_map(|o| Some((o.id, o.location?)))
+        .collect();
+    let mut centroids: Vec<(f64, f64)> =
+        located.iter().take(k.max(1)).map(|(_, loc)| *loc).collect();
+    let nearest = |centroids: &[(f64, f64)], (lat, lon): (f64, f64)| {
+        (0..centroids.len())
+            .min_by(|a, b| {
+                let d = |c: usize| (centroids[c].0 - lat).powi(2) + (centroids[c].1 - lon).powi(2);
+                d(*a).total_cmp(&d(*b))
+            })
+            .unwrap()
+    };
+    let mut membership: Vec<usize> = vec![0; located.len()];
+    for _ in 0..20 {
+        let next: Vec<usize> = located
+            .iter()
+            .map(|(_, loc)| nearest(&centroids, *loc))
+            .collect();
+        if next == membership {
+            break;
+        }
+        membership = next;
+        for (c, centroid) in centroids.iter_mut().enumerate() {
+            let members: Vec<(f64, f64)> = located
+                .iter()
+                .zip(&membership)
+                .filter(|(_, m)| **m == c)
+                .map(|((_, loc), _)| *loc)
+                .collect();
+            if !members.is_empty() {
+                let n = members.len() as f64;
+                *centroid = (
+                    members.iter().map(|l| l.0).sum::<f64>() / n,
+                    members.iter().map(|l| l.1).sum::<f64>() / n,
+                );
+            }
+        }
+    }
+
+    let mut clusters: Vec<Vec<u64>> = vec![vec![]; centroids.len()];
+    for ((order_id, _), c) in located.iter().zip(&membership) {
+        clusters[*c].push(*order_id);
+    }
+    clusters.push(
+        orders
+            .iter()
+            .filter(|o| o.location.is_none())
+            .map(|o| o.id)
+            .collect(),
+    );
+    clusters.retain(|cluster| !cluster.is_empty());
+    clusters
+}
+
+// Whole clusters from cluster_orders, each to the rider with the fewest
+// orders so far (lowest id on ties). Capacity is not checked, and without
+// riders nothing is assigned.
+#[must_use]
+fn compute_plan_clustered(riders: &[Rider], orders: &[Order], k: usize) -> Plan {
+    let mut plan = Plan::default();
+    for cluster in cluster_orders(orders, k) {
+        let least_loaded = riders
+            .iter()
+            .min_by_key(|r| (plan.get(&r.id).map_or(0, |o| o.len()), r.id));
+        if let Some(rider) = least_loaded {
+            plan.entry(rider.id).or_default().extend(cluster);
+        }
+    }
+    plan
+}
+
+// Places every order on the least-loaded rider with room, or none of them:
+// if one doesn't fit, the batch is taken back off and the plan is left as it
+// was. Returns where each order went.
+fn assign_transactional(
+    plan: &mut Plan,
+    riders: &[Rider],
+    order_ids: &[u64],
+) -> Result<HashMap<u64, u32>, ()> {
+    // (order, rider, whether the rider's queue was created for it)
+    let mut placed: Vec<(u64, u32, bool)> = Vec::new();
+    for order_id in order_ids {
+        let least_loaded = riders
+            .iter()
+            .map(|r| (plan.get(&r.id).map_or(0, |o| o.len()), r))
+            .filter(|(load, r)| r.has_room(*load))
+            .min_by_key(|(load, r)| (*load, r.id));
+        let Some((_, rider)) = least_loaded else {
+            // Each placement went to the back of a queue, undo newest first
+            for (_, rider_id, created) in placed.into_iter().rev() {
+                plan.get_mut(&rider_id).unwrap().pop();
+                if created {
+                    plan.remove(&rider_id);
+                }
+            }
+            return Err(());
+        };
+        placed.push((*order_id, rider.id, !plan.contains_key(&rider.id)));
+        plan.entry(rider.id).or_default().push(*order_id);
+    }
+    Ok(placed
+        .into_iter()
+        .map(|(order_id, rider_id, _)| (order_id, rider_id))
+        .collect())
+}
+
+// Uses as few riders as possible: the largest capacities (unlimited first,
+// ties to the lowest id) are filled one at a time. Returns the riders left
+// without orders, by ascending id. Orders beyond the total capacity are not
+// placed.
+#[must_use]
+fn compute_plan_min_riders(riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u32>) {
+    let mut plan = Plan::default();
+    let mut by_capacity = riders
+        .iter()
+        .sorted_by_key(|r| (std::cmp::Reverse(r.capacity.unwrap_or(u32::MAX)), r.id));
+    let mut current = by_capacity.next();
+    for order in orders {
+        while let Some(rider) = current {
+            if rider.has_room(plan.get(&rider.id).map_or(0, |o| o.len())) {
+                break;
+            }
+            current = by_capacity.next();
+        }
+        let Some(rider) = current else {
+            break;
+        };
+        plan.entry(rider.id).or_default().push(order.id);
+    }
+    let unused = riders
+        .iter()
+        .map(|r| r.id)
+        .filter(|id| !plan.contains_key(id))
+        .sorted()
+        .collect();
+    (plan, unused)
+}
+
+// Each order goes to the rider with room it has the highest affinity for,
+// missing pairs scoring zero. Ties go to the least-loaded rider, then the
+// lowest id. Orders no rider has room for are left out.
+#[must_use]
+fn compute_plan_affinity(
+    riders: &[Rider],
+    orders: &[Order],
+    affinity: &HashMap<(u64, u32), i32>,
+) -> Plan {
+    let mut plan = Plan::default();
+    for order in orders {
+        let best = riders
+            .iter()
+            .map(|r| (plan.get(&r.id).map_or(0, |o| o.len()), r))
+            .filter(|(load, r)| r.has_room(*load))
+            .min_by_key(|(load, r)| {
+                let score = affinity.get(&(order.id, r.id)).cloned().unwrap_or(0);
+                (std::cmp::Reverse(score), *load, r.id)
+            });
+        if let Some((_, rider)) = best {
+            plan.entry(rider.id).or_default().push(order.id);
+        }
+    }
+    plan
+}
+
+// Which rider last served each delivery address
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct History {
+    riders_by_address: HashMap<u64, u32>,
+}
+
+impl History {
+    // Remembers the rider of every planned order with an address, replacing
+    // older entries for the same address. Orders missing from `orders` are
+    // skipped, their address is unknown.
+    fn record(&mut self, plan: &Plan, orders: &[Order]) {
+        let addresses: HashMap<u64, u64> = orders
+            .iter()
+            .filter_map(|o| Some((o.id, o.address_id?)))
+            .collect();
+        for (rider_id, order_id) in manifest(plan)
+            .into_iter()
+            .flat_map(|(rider_id, queue)| queue.into_iter().map(move |o| (rider_id, o)))
+        {
+            if let Some(address_id) = addresses.get(&order_id) {
+                self.riders_by_address.insert(*address_id, rider_id);
+            }
+        }
+    }
+}
+
+// compute_plan_affinity with the historical rider of each order's address as
+// the only preference: orders go back to that rider while they have room and
+// otherwise to the least-loaded rider with room
+#[must_use]
+fn compute_plan_with_history(riders: &[Rider], orders: &[Order], history: &History) -> Plan {
+    let affinity: HashMap<(u64, u32), i32> = orders
+        .iter()
+        .filter_map(|o| {
+            let rider_id = history.riders_by_address.get(&o.address_id?)?;
+            Some(((o.id, *rider_id), 1))
+        })
+        .collect();
+    compute_plan_affinity(riders, orders, &affinity)
+}
+
+// The order's priority shrunk by exp(-rate * age)
+#[must_use]
+fn effective_priority(order: &Order, now: u64, rate: f64) -> f64 {
+    let age = now.saturating_sub(order.created_at) as f64;
+    order.priority as f64 * (-rate * age).exp()
+}
+
+// Replaces each priority with its effective_priority, rounded to the nearest
+// whole level
+fn decay_priorities(orders: &mut [Order], now: u64, rate: f64) {
+    for order in orders {
+        order.priority = effective_priority(order, now, rate).round() as u8;
+    }
+}
+
+// Least-loaded with room, taking orders by effective_priority (highest first,
+// input order among equals). Under capacity pressure the least urgent orders
+// are the ones dropped, returned as leftovers in input order.
+#[must_use]
+fn compute_plan_soft_cap(
+    riders: &[Rider],
+    orders: &[Order],
+    now: u64,
+    rate: f64,
+) -> (Plan, Vec<u64>) {
+    let by_priority: Vec<Order> = orders
+        .iter()
+        .cloned()
+        .sorted_by(|a, b| {
+            effective_priority(b, now, rate).total_cmp(&effective_priority(a, now, rate))
+        })
+        .collect();
+    let (plan, dropped) = compute_plan_least_loaded(riders, &by_priority);
+    let dropped: HashSet<u64> = dropped.into_iter().collect();
+    let leftovers = orders
+        .iter()
+        .map(|o| o.id)
+        .filter(|id| dropped.contains(id))
+        .collect();
+    (plan, leftovers)
+}
+
+// Least-loaded among the riders on shift at `now`
+#[must_use]
+fn compute_plan_at(riders: &[Rider], orders: &[Order], now: u64) -> (Plan, Vec<u64>) {
+    let on_shift: Vec<Rider> = riders.iter().filter(|r| r.on_shift(now)).cloned().collect();
+    compute_plan_least_loaded(&on_shift, orders)
+}
+
+// The closest located rider that still has room for an on-demand order, None
+// when the order has no location or every located rider is full
+#[must_use]
+fn dispatch_nearest_idle(plan: &Plan, riders: &[Rider], order: &Order) -> Option<u32> {
+    let to = order.location?;
+    riders
+        .iter()
+        .filter(|r| r.has_room(plan.get(&r.id).map_or(0, |o| o.len())))
+        .filter_map(|r| Some((haversine_km(r.location?, to), r.id)))
+        .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(_, rider_id)| rider_id)
+}
+
+// Nearest rider that still has room, moving outward when closer riders are
+// full. Orders that can't be placed by distance go to the least-loaded rider
+// with room; only orders no rider has room for are left over.
+#[must_use]
+fn compute_plan_cascade(riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+    let mut plan = Plan::default();
+    let mut leftovers = Vec::new();
+    for order in orders {
+        let load = |plan: &Plan, r: &Rider| plan.get(&r.id).map_or(0, |o| o.len());
+        let by_distance = dispatch_nearest_idle(&plan, riders, order);
+        let by_load = || {
+            riders
+                .iter()
+                .filter(|r| r.has_room(load(&plan, r)))
+                .min_by_key(|r| (load(&plan, r), r.id))
+                .map(|r| r.id)
+        };
+        match by_distance.or_else(by_load) {
+            Some(rider_id) => plan.entry(rider_id).or_default().push(order.id),
+            None => leftovers.push(order.id),
+        }
+    }
+    (plan, leftovers)
+}
+
+// Runtime-selectable assignment, e.g. from service configuration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Strategy {
+    RoundRobin,
+    LeastLoaded,
+    Nearest,
+    Cascade,
+}
+
+impl Strategy {
+    const ALL: [Strategy; 4] = [
+        Strategy::RoundRobin,
+        Strategy::LeastLoaded,
+        Strategy::Nearest,
+        Strategy::Cascade,
+    ];
+}
+
+#[must_use]
+fn compute_plan_with(strategy: Strategy, riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+    match strategy {
+        Strategy::RoundRobin if riders.is_empty() => {
+            (Plan::default(), orders.iter().map(|o| o.id).collect())
+        }
+        Strategy::RoundRobin => (compute_plan(riders, orders), vec![]),
+        Strategy::LeastLoaded => compute_plan_least_loaded(riders, orders),
+        Strategy::Nearest => compute_plan_nearest(riders, orders),
+        Strategy::Cascade => compute_plan_cascade(riders, orders),
+    }
+}
+
+trait AssignmentStrategy {
+    fn name(&self) -> &'static str;
+    // Returns the plan and the orders it could not assign
+    fn assign(&self, riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>);
+}
+
+struct RoundRobinStrategy;
+struct LeastLoadedStrategy;
+struct NearestStrategy;
+
+impl AssignmentStrategy for RoundRobinStrategy {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn assign(&self, riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+        compute_plan_with(Strategy::RoundRobin, riders, orders)
+    }
+}
+
+impl AssignmentStrategy for LeastLoadedStrategy {
+    fn name(&self) -> &'static str {
+        "least-loaded"
+    }
+
+    fn assign(&self, riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+        compute_plan_least_loaded(riders, orders)
+    }
+}
+
+impl AssignmentStrategy for NearestStrategy {
+    fn name(&self) -> &'static str {
+        "nearest"
+    }
+
+    fn assign(&self, riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+        compute_plan_nearest(riders, orders)
+    }
+}
+
+impl AssignmentStrategy for Strategy {
+    fn name(&self) -> &'static str {
+        match self {
+            Strategy::RoundRobin => "round-robin",
+            Strategy::LeastLoaded => "least-loaded",
+            Strategy::Nearest => "nearest",
+            Strategy::Cascade => "cascade",
+        }
+    }
+
+    fn assign(&self, riders: &[Rider], orders: &[Order]) -> (Plan, Vec<u64>) {
+        compute_plan_with(*self, riders, orders)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct StrategyReport {
+    name: &'static str,
+    // Loads are over all given riders, so riders left without orders count as 0
+    max_load: usize,
+    min_load: usize,
+    leftovers: usize,
+    // Only reported when every rider and order has a location
+    total_distance_km: Option<f64>,
+}
+
+#[must_use]
+fn compare_strategies(
+    strategies: &[&dyn AssignmentStrategy],
+    riders: &[Rider],
+    orders: &[Order],
+) -> Vec<StrategyReport> {
+    let has_coordinates =
+        riders.iter().all(|r| r.location.is_some()) && orders.iter().all(|o| o.location.is_some());
+    strategies
+        .iter()
+        .map(|strategy| {
+            let (plan, leftovers) = strategy.assign(riders, orders);
+            let (min_load, max_load) = riders
+                .iter()
+                .map(|r| plan.get(&r.id).map_or(0, |o| o.len()))
+                .minmax()
+                .into_option()
+                .unwrap_or((0, 0));
+            StrategyReport {
+                name: strategy.name(),
+                max_load,
+                min_load,
+                leftovers: leftovers.len(),
+                total_distance_km: has_coordinates.then(|| plan_cost(&plan, riders, orders, 1.0)),
+            }
+        })
+        .collect()
+}
+
+// Which rider/order constraints compute_plan_full enforces
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Constraints {
+    capacity: bool,
+    zone: bool,
+    tags: bool,
+    skill: bool,
+}
+
+impl Constraints {
+    const ALL: Constraints = Constraints {
+        capacity: true,
+        zone: true,
+        tags: true,
+        skill: true,
+    };
+}
+
+// Why an order could not be placed: the first constraint that left it without
+// riders, checked as zone, tags, skill and capacity last
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UnassignableReason {
+    NoRiders,
+    NoRiderInZone,
+    MissingTags,
+    InsufficientSkill,
+    NoCapacity,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct PlanResult {
+    plan: Plan,
+    // Orders that can't be placed, in input order
+    dead_letter: Vec<(u64, UnassignableReason)>,
+}
+
+// Least-loaded assignment under every enabled constraint
+#[must_use]
+fn compute_plan_full(riders: &[Rider], orders: &[Order], constraints: &Constraints) -> PlanResult {
+    let mut result = PlanResult::default();
+    for order in orders {
+        let in_zone = |r: &Rider| !constraints.zone || r.zone == order.zone;
+        let has_tags =
+            |r: &Rider| !constraints.tags || r.tags & order.required_tags == order.required_tags;
+        let skilled = |r: &Rider| !constraints.skill || r.skill >= order.complexity;
+        let least_loaded = riders
+            .iter()
+            .filter(|r| in_zone(r) && has_tags(r) && skilled(r))
+            .map(|r| (result.plan.get(&r.id).map_or(0, |o| o.len()), r))
+            .filter(|(load, r)| !constraints.capacity || r.has_room(*load))
+            .min_by_key(|(load, r)| (*load, r.id));
+        if let Some((_, rider)) = least_loaded {
+            result.plan.entry(rider.id).or_default().push(order.id);
+            continue;
+        }
+        let reason = if riders.is_empty() {
+            UnassignableReason::NoRiders
+        } else if !riders.iter().any(in_zone) {
+            UnassignableReason::NoRiderInZone
+        } else if !riders.iter().any(|r| in_zone(r) && has_tags(r)) {
+            UnassignableReason::MissingTags
+        } else if !riders
+            .iter()
+            .any(|r| in_zone(r) && has_tags(r) && skilled(r))
+        {
+            UnassignableReason::InsufficientSkill
+        } else {
+            UnassignableReason::NoCapacity
+        };
+        result.dead_letter.push((order.id, reason));
+    }
+    result
+}
+
+// The first thing that rules out placing every order, see is_feasible
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Infeasibility {
+    NoRiders,
+    ZoneWithoutRiders {
+        zone: u16,
+    },
+    MissingTags {
+        order_id: u64,
+    },
+    InsufficientSkill {
+        order_id: u64,
+    },
+    // Per zone when zones are enforced, otherwise over all riders (zone None)
+    NotEnoughCapacity {
+        zone: Option<u16>,
+        orders: usize,
+        capacity: u64,
+    },
+}
+
+// Checks, in this order, that there are riders for the orders, that every zone
+// with orders has a rider, that every order has a rider with its tags and then
+// skill (input order), and that the riders' capacities add up to the order
+// count. These are necessary conditions only: compute_plan_full can still
+// dead-letter an order when the room is on riders it can't use.
+fn is_feasible(
+    riders: &[Rider],
+    orders: &[Order],
+    constraints: &Constraints,
+) -> Result<(), Infeasibility> {
+    if orders.is_empty() {
+        return Ok(());
+    }
+    if riders.is_empty() {
+        return Err(Infeasibility::NoRiders);
+    }
+    let in_zone = |r: &Rider, o: &Order| !constraints.zone || r.zone == o.zone;
+    if let Some(zone) = orders
+        .iter()
+        .filter(|o| !riders.iter().any(|r| in_zone(r, o)))
+        .map(|o| o.zone)
+        .min()
+    {
+        return Err(Infeasibility::ZoneWithoutRiders { zone });
+    }
+    let has_tags = |r: &Rider, o: &Order| {
+        in_zone(r, o) && (!constraints.tags || r.tags & o.required_tags == o.required_tags)
+    };
+    let skilled =
+        |r: &Rider, o: &Order| has_tags(r, o) && (!constraints.skill || r.skill >= o.complexity);
+    for order in orders {
+        if !riders.iter().any(|r| has_tags(r, order)) {
+            return Err(Infeasibility::MissingTags { order_id: order.id });
+        }
+        if !riders.iter().any(|r| skilled(r, order)) {
+            return Err(Infeasibility::InsufficientSkill { order_id: order.id });
+        }
+    }
+    if !constraints.capacity {
+        return Ok(());
+    }
+    let zones: Vec<Option<u16>> = if constraints.zone {
+        orders
+            .iter()
+            .map(|o| Some(o.zone))
+            .sorted()
+            .dedup()
+            .collect()
+    } else {
+        vec![None]
+    };
+    for zone in zones {
+        let in_scope = |z: u16| zone.is_none_or(|zone| zone == z);
+        let demand = orders.iter().filter(|o| in_scope(o.zone)).count();
+        let capacity: Option<u64> = riders
+            .iter()
+            .filter(|r| in_scope(r.zone))
+            .map(|r| r.capacity.map(u64::from))
+            .sum();
+        if let Some(capacity) = capacity.filter(|c| *c < demand as u64) {
+            return Err(Infeasibility::NotEnoughCapacity {
+                zone,
+                orders: demand,
+                capacity,
+            });
+        }
+    }
+    Ok(())
+}
+
+// A plan shared between threads. A thread that panicked while holding the
+// lock leaves it poisoned; the plan is still usable, since apply only stores a
+// plan once the event has been fully processed, so the poison is ignored.
+#[derive(Clone, Debug, Default)]
+struct SharedPlan(Arc<RwLock<Plan>>);
+
+impl SharedPlan {
+    fn new(plan: Plan) -> Self {
+        SharedPlan(Arc::new(RwLock::new(plan)))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Plan> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Plan> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn apply(&self, event: Event) -> EventOutcome {
+        let mut plan = self.write();
+        let (next, outcome) = process_event(plan.clone(), event);
+        *plan = next;
+        outcome
+    }
+}
+
+// A plan together with the riders and orders it refers to, for decisions
+// that need more than ids
+#[derive(Clone, Debug, Default)]
+struct Dispatcher {
+    plan: Plan,
+    riders: HashMap<u32, Rider>,
+    orders: HashMap<u64, Order>,
+    // Orders that found no rider with room, oldest first
+    pending: Vec<u64>,
+    // Orders pulled from dispatch, neither assigned nor canceled
+    held: HashSet<u64>,
+    tie_break: TieBreak,
+    last_tie_winner: Option<u32>,
+    // Events applied so far, for rejection cooldowns
+    clock: MoveClock,
+    // Orders kept out of automatic assignment for manual review
+    reserved: HashSet<u64>,
+    // How many rejected orders each rider has been handed
+    rejections_taken: HashMap<u32, u64>,
+}
+
+// How least-loaded assignment chooses between riders with the same load
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum TieBreak {
+    #[default]
+    LowestId,
+    HighestId,
+    // Rotates through the tied riders, starting after the last one picked
+    RoundRobin,
+    // Seeded pseudo-random pick
+    Random(u64),
+}
+
+impl Dispatcher {
+    fn new(riders: &[Rider], orders: &[Order]) -> Self {
+        let mut plan = compute_plan(riders, orders);
+        // Idle riders still need an entry to be picked up by later assignments
+        for rider in riders {
+            plan.entry(rider.id).or_default();
+        }
+        Dispatcher {
+            plan,
+            riders: riders.iter().map(|r| (r.id, r.clone())).collect(),
+            orders: orders.iter().map(|o| (o.id, o.clone())).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn from_plan(plan: Plan) -> Self {
+        Dispatcher {
+            plan,
+            ..Default::default()
+        }
+    }
+
+    // Rebuilds the plan from scratch with compute_plan over the known riders
+    // and every order that isn't reserved or held, both by ascending id.
+    // Without riders the orders wait in the pending queue.
+    fn recompute(&mut self) {
+        let riders: Vec<Rider> = self
+            .riders
+            .values()
+            .cloned()
+            .sorted_by_key(|r| r.id)
+            .collect();
+        let orders: Vec<Order> = self
+            .orders
+            .values()
+            .filter(|o| !self.reserved.contains(&o.id) && !self.held.contains(&o.id))
+            .cloned()
+            .sorted_by_key(|o| o.id)
+            .collect();
+        if riders.is_empty() {
+            self.plan.clear();
+            self.pending = orders.iter().map(|o| o.id).collect();
+            return;
+        }
+        self.plan = compute_plan(&riders, &orders);
+        for rider in &riders {
+            self.plan.entry(rider.id).or_default();
+        }
+        self.pending.clear();
+    }
+
+    // Takes the order out of the plan and the pending queue until unreserved
+    fn reserve(&mut self, order_id: u64) {
+        self.reserved.insert(order_id);
+        remove_order(&mut self.plan, order_id);
+        self.pending.retain(|v| *v != order_id);
+    }
+
+    // Makes the order assignable again, it isn't placed until assigned
+    fn unreserve(&mut self, order_id: u64) {
+        self.reserved.remove(&order_id);
+    }
+
+    fn is_assigned(&self, order_id: u64) -> bool {
+        self.plan.values().flatten().any(|v| *v == order_id)
+    }
+
+    fn has_capacity(&self, rider_id: u32) -> bool {
+        let load = self.plan.get(&rider_id).map_or(0, |orders| orders.len());
+        self.riders.get(&rider_id).is_none_or(|r| r.has_room(load))
+    }
+
+    // Prefers a rider already delivering to the order's address, otherwise the
+    // least-loaded rider with spare capacity. Reserved orders are never placed.
+    fn assign_one(&mut self, order_id: u64) -> Option<u32> {
+        if self.reserved.contains(&order_id) {
+            return None;
+        }
+        let address_id = self.orders.get(&order_id).and_then(|o| o.address_id);
+        let serves_address = |orders: &Vec<u64>| {
+            address_id.is_some()
+                && orders
+                    .iter()
+                    .any(|id| self.orders.get(id).and_then(|o| o.address_id) == address_id)
+        };
+        // All riders sharing the lowest load, by ascending id
+        let least_loaded = |bundled_only: bool| {
+            self.plan
+                .iter()
+                .filter(|(rider_id, orders)| {
+                    self.has_capacity(**rider_id) && (!bundled_only || serves_address(orders))
+                })
+                .map(|(rider_id, orders)| (orders.len(), *rider_id))
+                .sorted()
+                .group_by(|(load, _)| *load)
+                .into_iter()
+                .next()
+                .map(|(_, tied)| tied.map(|(_, rider_id)| rider_id).collect::<Vec<_>>())
+        };
+        let tied = least_loaded(true).or_else(|| least_loaded(false))?;
+        let rider_id = self.break_tie(&tied)?;
+        self.plan.entry(rider_id).or_default().push(order_id);
+        Some(rider_id)
+    }
+
+    // Picks among equally loaded riders, given in ascending id order. None
+    // when there is nobody to pick from.
+    fn break_tie(&mut self, tied: &[u32]) -> Option<u32> {
+        let rider_id = match &mut self.tie_break {
+            TieBreak::LowestId => tied.first(),
+            TieBreak::HighestId => tied.last(),
+            TieBreak::RoundRobin => self
+                .last_tie_winner
+                .and_then(|last| tied.iter().find(|id| **id > last))
+                .or(tied.first()),
+            TieBreak::Random(state) => {
+                // splitmix64, the seed doubles as the generator state
+                *state = state.wrapping_add(0x9e3779b97f4a7c15);
+                let len = tied.len() as u64;
+                mix64(*state)
+                    .checked_rem(len)
+                    .and_then(|idx| tied.get(idx as usize))
+            }
+        }
+        .copied()?;
+        self.last_tie_winner = Some(rider_id);
+        Some(rider_id)
+    }
+
+    fn assign_one_or_queue(&mut self, order_id: u64) -> Option<u32> {
+        let assigned = self.assign_one(order_id);
+        if assigned.is_none() && !self.pending.contains(&order_id) {
+            self.pending.push(order_id);
+        }
+        assigned
+    }
+
+    // Retries queued orders in arrival order, returning the ones now assigned
+    fn drain_pending(&mut self) -> Vec<u64> {
+        let mut assigned = vec![];
+        for order_id in std::mem::take(&mut self.pending) {
+            match self.assign_one(order_id) {
+                Some(_) => assigned.push(order_id),
+                None => self.pending.push(order_id),
+            }
+        }
+        assigned
+    }
+
+    fn add_rider(&mut self, rider: Rider) {
+        let rider_id = rider.id;
+        self.riders.insert(rider_id, rider);
+        self.apply(Event::RiderAdded { rider_id });
+    }
+
+    fn add_order(&mut self, order: Order) -> Option<u32> {
+        let order_id = order.id;
+        self.orders.insert(order_id, order);
+        match self.apply(Event::OrderAdded { order_id }) {
+            EventOutcome::Assigned { rider_id, .. } => Some(rider_id),
+            _ => rider_of(&self.plan, order_id),
+        }
+    }
+
+    fn process(&mut self, event: Event) -> EventOutcome {
+        let (plan, outcome) = process_event(std::mem::take(&mut self.plan), event);
+        self.plan = plan;
+        outcome
+    }
+
+    // Like process_event, but additions use the dispatcher's own assign_one,
+    // held orders are tracked and rejections respect the cooldown
+    fn apply(&mut self, event: Event) -> EventOutcome {
+        if !self.clock.tick(event) {
+            return ignored(IgnoreReason::Cooldown);
+        }
+        let outcome = self.apply_now(event);
+        self.clock.record(&outcome);
+        outcome
+    }
+
+    fn apply_now(&mut self, event: Event) -> EventOutcome {
+        match event {
+            Event::OrderAdded { order_id } => {
+                if self.is_assigned(order_id) {
+                    return ignored(IgnoreReason::AlreadyAssigned);
+                }
+                if self.reserved.contains(&order_id) {
+                    return ignored(IgnoreReason::Reserved);
+                }
+                match self.assign_one(order_id) {
+                    Some(rider_id) => EventOutcome::Assigned { order_id, rider_id },
+                    None => ignored(IgnoreReason::NoRiderAvailable),
+                }
+            }
+            Event::OrderHeld { order_id } => {
+                let outcome = self.process(event);
+                if let EventOutcome::Held { .. } = outcome {
+                    self.held.insert(order_id);
+                }
+                outcome
+            }
+            Event::OrderReleased { order_id } => {
+                if !self.held.contains(&order_id) {
+                    return ignored(IgnoreReason::NotHeld);
+                }
+                match self.assign_one(order_id) {
+                    Some(rider_id) => {
+                        self.held.remove(&order_id);
+                        EventOutcome::Assigned { order_id, rider_id }
+                    }
+                    None => ignored(IgnoreReason::NoRiderAvailable),
+                }
+            }
+            Event::RiderRejected { rider_id, order_id } => {
+                let outcome =
+                    reject_order(&mut self.plan, rider_id, order_id, &self.rejections_taken);
+                if let EventOutcome::Reassigned { to, .. } = outcome {
+                    *self.rejections_taken.entry(to).or_default() += 1;
+                }
+                outcome
+            }
+            Event::OrderCanceled { order_id } => {
+                let was_held = self.held.remove(&order_id);
+                match self.process(event) {
+                    EventOutcome::Ignored { .. } if was_held => EventOutcome::Canceled {
+                        order_id,
+                        moves: vec![],
+                    },
+                    outcome => outcome,
+                }
+            }
+            _ => self.process(event),
+        }
+    }
+}
+
+// Prints the event log if the test holding it panics
+struct LogOnPanic(String);
+
+impl Drop for LogOnPanic {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!("events:\n{}", self.0);
+        }
+    }
+}
+
+// Every starting order ended up either canceled or still around, and none
+// of them both
+fn assert_partition(starting: &HashSet<u64>, canceled: &HashSet<u64>, remaining: &HashSet<u64>) {
+    let both: Vec<_> = canceled.intersection(remaining).sorted().collect();
+    assert!(both.is_empty(), "canceled orders came back: {both:?}");
+    assert_eq!(starting, &canceled.union(remaining).cloned().collect());
+}
+
+fn fuzz_plan() -> Plan {
+    Plan::from_iter([(1, vec![10, 11, 12]), (2, vec![20]), (3, vec![])])
+}
+
+
+
+
+
+
+
+
+proptest! {
+    #[test]
+    fn all_riders_get_orders(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.len() <= orders.len());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+        println!("{} {}", riders.len(), orders.len());
+
+        let plan = compute_plan(&riders, &orders);
+        for rider in riders {
+            assert!(plan.contains_key(&rider.id));
+            assert!(!plan[&rider.id].is_empty());
+        }
+    }
+
+
+
+
+
+
+
+
+
+
+    #[test]
+    fn all_orders_are_assigned(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.len() <= orders.len());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        println!("{} {}", riders.len(), orders.len());
+
+        let plan = compute_plan(&riders, &orders);
+        for order in orders {
+            assert!(plan.values().any(|v| v.contains(&order.id)));
+        }
+    }
+
+
+
+
+
+
+
+
+
+
+    #[test]
+    fn every_order_is_assigned_exactly_once(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let plan = compute_plan(&riders, &orders);
+        assert_eq!(plan.values().map(Vec::len).sum::<usize>(), orders.len());
+        assert!(plan.values().flatten().all_unique());
+    }
+
+    #[test]
+    fn waves_cover_the_full_plan(riders: Vec<Rider>, orders: Vec<Order>, wave_size in 1..20usize) {
+        prop_assume!(!riders.is_empty());
+
+        let waves = compute_plan_waves(&riders, &orders, wave_size);
+        assert_eq!(waves.len(), orders.len().div_ceil(wave_size));
+        for wave in waves.iter().rev().skip(1) {
+            assert_eq!(wave.values().map(Vec::len).sum::<usize>(), wave_size);
+        }
+        let full = compute_plan(&riders, &orders);
+        assert_eq!(
+            waves.iter().flat_map(|wave| wave.values().flatten()).sorted().collect::<Vec<_>>(),
+            full.values().flatten().sorted().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn time_spread_is_within_the_longest_order(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let plan = compute_plan_by_time(&riders, &orders);
+        let times = projected_time(&plan, &orders);
+        let (min, max) = riders
+            .iter()
+            .map(|r| times.get(&r.id).cloned().unwrap_or(0))
+            .minmax()
+            .into_option()
+            .unwrap();
+        let longest = orders.iter().map(|o| o.service_minutes).max().unwrap_or(0);
+        assert!(max - min <= longest, "spread {} over longest order {longest}", max - min);
+        assert_eq!(plan.values().map(|queue| queue.len()).sum::<usize>(), orders.len());
+    }
+
+    #[test]
+    fn assignment_matrix_has_one_rider_per_order(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let plan = compute_plan(&riders, &orders);
+        let matrix = assignment_matrix(&plan, &riders, &orders);
+        assert_eq!(matrix.len(), riders.len());
+        for (row, rider) in matrix.iter().zip(&riders) {
+            let load = plan.get(&rider.id).map_or(0, |queue| queue.len());
+            assert_eq!(row.iter().map(|cell| *cell as usize).sum::<usize>(), load);
+        }
+        for j in 0..orders.len() {
+            assert_eq!(matrix.iter().map(|row| row[j]).sum::<u8>(), 1);
+        }
+    }
+
+    // No prop_assume! on purpose: duplicate ids, empty inputs, odd
+    // coordinates and out-of-range event indices must all be survivable
+    #[test]
+    fn public_api_never_panics(riders: Vec<Rider>, orders: Vec<Order>, test_events: Vec<TestEvent>, k in 0..5usize, capacity in 0..4u32, bytes: Vec<u8>, handle: u32) {
+        let plan = compute_plan(&riders, &orders);
+        for strategy in Strategy::ALL {
+            let _ = compute_plan_with(strategy, &riders, &orders);
+        }
+        let _ = compute_plan_full(&riders, &orders, &Constraints::ALL);
+        let _ = is_feasible(&riders, &orders, &Constraints::ALL);
+        let _ = compute_plan_report_idle(&riders, &orders);
+        let _ = compute_plan_capped_rr(&riders, &orders);
+        let _ = compute_plan_skill(&riders, &orders);
+        let _ = compute_plan_tiered(&riders, &orders);
+        let _ = compute_plan_waves(&riders, &orders, k);
+        let _ = compute_plan_scored(&riders, &orders, &ScoreWeights { distance: 1.0, load: 1.0, rating: 1.0 });
+        let _ = compute_plan_clustered(&riders, &orders, k);
+        let _ = compute_plan_min_riders(&riders, &orders);
+        let _ = compute_plan_by_time(&riders, &orders);
+        let _ = compute_plan_sla(&riders, &orders, k);
+        let _ = compute_plan_nearest_with_distances(&riders, &orders);
+        let _ = compute_plan_soft_cap(&riders, &orders, 0, 0.1);
+        let _ = compute_plan_at(&riders, &orders, 0);
+        let _ = compute_plan_with_overflow(&riders, &orders, 0);
+        let _ = compute_plan_with_history(&riders, &orders, &History::default());
+        let _ = compute_plan_streaming(&riders, orders.iter().cloned());
+        let _ = reassign_fresh(&riders, &orders);
+        let _ = cluster_orders(&orders, k);
+        let _ = plan_cost(&plan, &riders, &orders, 1.0);
+        let _ = projected_time(&plan, &orders);
+        let _ = assignment_matrix(&plan, &riders, &orders);
+        let _ = orphaned_orders(&plan, &riders);
+        let _ = fairness_report(&plan);
+        let _ = assignment_quality(&plan);
+        let _ = plan_metrics(&plan);
+        let _ = validate_plan(&plan);
+        #[cfg(feature = "bincode")]
+        {
+            let _ = plan_from_bytes(&bytes);
+            let _ = plan_from_bytes(&[PLAN_MAGIC.as_slice(), &[PLAN_FORMAT_VERSION], &bytes].concat());
+        }
+        let mut interner = Interner::default();
+        for order in &orders {
+            interner.intern(order.id);
+        }
+        let _ = interner.resolve(handle);
+        let _ = recommend_scaling(&plan, orders.len(), capacity);
+        let _ = riders_needed(orders.len(), capacity);
+        if let Some(order) = orders.first() {
+            let _ = dispatch_nearest_idle(&plan, &riders, order);
+        }
+        let mut zoned = plan.clone();
+        recompute_zone(&mut zoned, &riders, &orders, 0);
+        let mut cascaded = plan.clone();
+        let _ = cancel_cascade(&mut cascaded, &orders, orders.first().map_or(0, |o| o.id));
+
+        let events: Vec<Event> = test_events.into_iter().map(|e| e.into_event(&plan)).collect();
+        let _ = detect_reassignment_cycle(&events);
+        let _ = coalesce(&events);
+        let _ = replay(plan.clone(), &events, &mut MoveClock::default());
+        let _ = process_events_rate_limited(plan.clone(), &events, k, &mut MoveClock::default());
+        let mut dispatcher = Dispatcher::new(&riders, &orders);
+        for event in events {
+            dispatcher.apply(event);
+        }
+    }
+
+    #[test]
+    fn nearest_stays_within_rider_radius(
+        riders in prop::collection::vec(
+            (arb_located_rider(), prop::option::of(0.0..3000.0f64))
+                .prop_map(|(rider, max_radius_km)| Rider { max_radius_km, ..rider }),
+            0..10,
+        ),
+        orders in prop::collection::vec(arb_located_order(), 0..20),
+    ) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+        // Nobody is zero km away, so a zero radius reaches nothing
+        prop_assume!(orders.iter().all(|o| riders.iter().all(|r| r.location != o.location)));
+
+        let (plan, leftovers) = compute_plan_nearest(&riders, &orders);
+        assert_eq!(plan.values().flatten().count() + leftovers.len(), orders.len());
+        let order_locations: HashMap<u64, (f64, f64)> =
+            orders.iter().map(|o| (o.id, o.location.unwrap())).collect();
+        for rider in &riders {
+            for order_id in plan.get(&rider.id).into_iter().flatten() {
+                assert!(rider.within_radius(order_locations[order_id]));
+            }
+        }
+
+        let homebound: Vec<Rider> = riders
+            .iter()
+            .map(|r| Rider { max_radius_km: Some(0.0), ..r.clone() })
+            .collect();
+        let (plan, leftovers) = compute_plan_nearest(&homebound, &orders);
+        assert!(plan.is_empty());
+        assert_eq!(leftovers, orders.iter().map(|o| o.id).collect::<Vec<_>>());
+    }
+
+    // Acceptance test for sorting riders by id inside compute_plan. Round-robin
+    // still deals in input order, so this is a known failure until then:
+    // `cargo test -- --ignored compute_plan_ignores_rider_order`
+    #[test]
+    #[ignore]
+    fn compute_plan_ignores_rider_order(
+        riders: Vec<Rider>,
+        orders: Vec<Order>,
+        permutations in prop::collection::vec(prop::collection::vec(any::<usize>(), 0..16), 1..5),
+    ) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+
+        let plan = compute_plan(&riders, &orders);
+        for swaps in permutations {
+            // Fisher-Yates, driven by the generated indices
+            let mut shuffled = riders.clone();
+            for (i, j) in swaps.into_iter().enumerate().take(shuffled.len()) {
+                shuffled.swap(i, j % (i + 1));
+            }
+            assert!(plans_equivalent(&compute_plan(&shuffled, &orders), &plan));
+        }
+    }
+
+    #[test]
+    fn leftover_handler_sees_the_capped_leftovers(riders: Vec<Rider>, orders: Vec<Order>) {
+        let mut handled = vec![];
+        let plan = compute_plan_with_leftover_handler(&riders, &orders, &mut |order_id| {
+            handled.push(order_id)
+        });
+        let (capped, leftovers) = compute_plan_capped_rr(&riders, &orders);
+        assert_eq!(plan, capped);
+        assert_eq!(handled, leftovers.into_iter().sorted().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn value_spread_is_within_the_largest_order(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let plan = compute_plan_by_value(&riders, &orders);
+        let values = rider_values(&plan, &orders);
+        let (min, max) = riders
+            .iter()
+            .map(|r| values.get(&r.id).cloned().unwrap_or(0))
+            .minmax()
+            .into_option()
+            .unwrap();
+        let largest = orders.iter().map(|o| o.value).max().unwrap_or(0);
+        assert!(max - min <= largest, "spread {} over largest order {largest}", max - min);
+        assert_eq!(
+            plan.values().flatten().cloned().sorted().collect::<Vec<_>>(),
+            orders.iter().map(|o| o.id).sorted().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn orders_are_assigned_in_an_even_way(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.len() <= orders.len());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let plan = compute_plan(&riders, &orders);
+        let (min_orders, max_orders) = plan.values().map(|orders| orders.len()).minmax().into_option().unwrap();
+        assert!(1 >= max_orders-min_orders, "min: {}, max: {}", min_orders, max_orders);
+    }
+
+
+
+
+
+
+
+
+
+    #[test]
+    fn events_over_time(starting_plan in arb_plan(), test_events: Vec<TestEvent>) {
+        prop_assume!(starting_plan.len() > 1);
+        prop_assume!(starting_plan.values().all(|orders| !orders.is_empty()));
+        prop_assume!(starting_plan.values().flatten().all_unique());
+
+        let events : Vec<Event> = test_events.into_iter().map(|test_event| test_event.into_event(&starting_plan)).collect();
+        let canceled_orders : HashSet<_> = events.iter()
+            .flat_map(|e| if let Event::OrderCanceled{order_id} = e { Some(*order_id) } else { None })
+            .collect();
+        println!("total starting orders {}, events {}", starting_plan.values().map(|v| v.len()).sum::<usize>(), canceled_orders.len());
+        let _log = LogOnPanic(event_log(&events));
+        let mut current_plan = starting_plan.clone();
+        // process_event forgets held orders, so the test keeps them
+        let mut held_orders = HashSet::new();
+        for event in events {
+            let orders_before : HashSet<_> = current_plan.values().flatten().cloned().collect();
+            let outcome;
+            (current_plan, outcome) = process_event(current_plan, event);
+            match (event, outcome) {
+                (_, EventOutcome::Held { order_id }) => { held_orders.insert(order_id); }
+                (Event::OrderCanceled { order_id }, _) => { held_orders.remove(&order_id); }
+                _ => {}
+            }
+            if let Event::RiderRejected{rider_id,order_id} = event {
+                let orders_after : HashSet<_> = current_plan.values().flatten().cloned().collect();
+                assert_eq!(orders_before, orders_after);
+                assert!(!current_plan[&rider_id].contains(&order_id));
+            }
+            if let Event::RiderAdded{rider_id} = event {
+                let orders_after : HashSet<_> = current_plan.values().flatten().cloned().collect();
+                assert_eq!(orders_before, orders_after);
+                assert!(current_plan.contains_key(&rider_id));
+                assert!(load_spread(&current_plan) <= 1);
+            }
+        }
+        let remaining_orders : HashSet<u64> = current_plan.values().flatten().cloned().collect();
+        assert!(canceled_orders.iter().all(|canceled| !remaining_orders.contains(canceled) && !held_orders.contains(canceled)));
+        assert!(held_orders.iter().all(|held| !remaining_orders.contains(held)));
+        assert_partition(
+            &starting_plan.values().flatten().cloned().collect(),
+            &canceled_orders,
+            &remaining_orders.union(&held_orders).cloned().collect(),
+        );
+    }
+
+    // events_over_time through Dispatcher, which keeps the held set itself
+    #[test]
+    fn dispatcher_events_over_time(starting_plan in arb_plan(), test_events: Vec<TestEvent>) {
+        prop_assume!(starting_plan.len() > 1);
+        prop_assume!(starting_plan.values().all(|orders| !orders.is_empty()));
+        prop_assume!(starting_plan.values().flatten().all_unique());
+
+        let events : Vec<Event> = test_events.into_iter().map(|test_event| test_event.into_event(&starting_plan)).collect();
+        let canceled_orders : HashSet<_> = events.iter()
+            .flat_map(|e| if let Event::OrderCanceled{order_id} = e { Some(*order_id) } else { None })
+            .collect();
+        println!("total starting orders {}, events {}", starting_plan.values().map(|v| v.len()).sum::<usize>(), canceled_orders.len());
+        let _log = LogOnPanic(event_log(&events));
+        let mut dispatcher = Dispatcher::from_plan(starting_plan.clone());
+        for event in events {
+            let orders_before : HashSet<_> = dispatcher.plan.values().flatten().cloned().collect();
+            dispatcher.apply(event);
+            let current_plan = &dispatcher.plan;
+            if let Event::RiderRejected{rider_id,order_id} = event {
+                let orders_after : HashSet<_> = current_plan.values().flatten().cloned().collect();
+                assert_eq!(orders_before, orders_after);
+                assert!(!current_plan[&rider_id].contains(&order_id));
+            }
+            if let Event::RiderAdded{rider_id} = event {
+                let orders_after : HashSet<_> = current_plan.values().flatten().cloned().collect();
+                assert_eq!(orders_before, orders_after);
+                assert!(current_plan.contains_key(&rider_id));
+                assert!(load_spread(current_plan) <= 1);
+            }
+        }
+        let remaining_orders : HashSet<u64> = dispatcher.plan.values().flatten().cloned().collect();
+        let held_orders = &dispatcher.held;
+        assert!(canceled_orders.iter().all(|canceled| !remaining_orders.contains(canceled) && !held_orders.contains(canceled)));
+        assert!(held_orders.iter().all(|held| !remaining_orders.contains(held)));
+        assert_partition(
+            &starting_plan.values().flatten().cloned().collect(),
+            &canceled_orders,
+            &remaining_orders.union(held_orders).cloned().collect(),
+        );
+    }
+
+    #[test]
+    fn malformed_events_are_ignored_among_valid_ones(
+        starting_plan in prop::collection::hash_map(any::<u32>(), prop::collection::vec(any::<u64>(), 1..8), 2..8)
+            .prop_map(|plan| plan.into_iter().collect::<Plan>()),
+        steps: Vec<(TestEvent, u8)>,
+    ) {
+        prop_assume!(starting_plan.values().flatten().all_unique());
+
+        let valid: Vec<Event> = steps.iter().map(|(test_event, _)| test_event.clone().into_event(&starting_plan)).collect();
+        let starting: HashSet<u64> = starting_plan.values().flatten().cloned().collect();
+        let added: HashSet<u32> = valid.iter()
+            .filter_map(|e| if let Event::RiderAdded { rider_id } = e { Some(*rider_id) } else { None })
+            .collect();
+        let no_such_rider = (0..).find(|id| !starting_plan.contains_key(id) && !added.contains(id)).unwrap();
+        let no_such_order = (0..).find(|id| !starting.contains(id)).unwrap();
+
+        let mut dispatcher = Dispatcher::from_plan(starting_plan.clone());
+        let mut canceled = HashSet::new();
+        for (event, (_, kind)) in valid.into_iter().zip(steps) {
+            let malformed = match kind % 3 {
+                0 => None,
+                1 => Some(Event::OrderReassigned {
+                    order_id: dispatcher.plan.values().flatten().min().cloned().unwrap_or(no_such_order),
+                    to: no_such_rider,
+                }),
+                _ => Some(Event::OrderCanceled { order_id: no_such_order }),
+            };
+            match malformed {
+                Some(malformed) => {
+                    let (plan, held) = (dispatcher.plan.clone(), dispatcher.held.clone());
+                    let outcome = dispatcher.apply(malformed);
+                    assert!(matches!(outcome, EventOutcome::Ignored { .. }), "{malformed:?} gave {outcome:?}");
+                    assert_eq!(dispatcher.plan, plan);
+                    assert_eq!(dispatcher.held, held);
+                }
+                None => {
+                    if let EventOutcome::Canceled { order_id, .. } = dispatcher.apply(event) {
+                        canceled.insert(order_id);
+                    }
+                }
+            }
+        }
+        let remaining: HashSet<u64> = dispatcher.plan.values().flatten().cloned().collect();
+        assert_partition(&starting, &canceled, &remaining.union(&dispatcher.held).cloned().collect());
+    }
+
+    #[test]
+    fn single_events_conserve_orders(event in arb_event_for(&fuzz_plan())) {
+        let (plan, outcome) = process_event(fuzz_plan(), event);
+
+        let mut expected: Vec<u64> = fuzz_plan().values().flatten().cloned().collect();
+        match outcome {
+            EventOutcome::Canceled { order_id, .. } | EventOutcome::Held { order_id } => {
+                expected.retain(|v| *v != order_id)
+            }
+            EventOutcome::Assigned { order_id, .. } => expected.push(order_id),
+            _ => {}
+        }
+        assert_eq!(plan.values().flatten().cloned().sorted().collect::<Vec<_>>(), expected.into_iter().sorted().collect::<Vec<_>>());
+        assert_eq!(validate_plan(&plan), Ok(()));
+    }
+
+    #[test]
+    fn rebalance_to_weights_matches_shares(mut plan in arb_plan(), weights: Vec<u8>) {
+        prop_assume!(plan.values().flatten().all_unique());
+        let weights: HashMap<u32, u32> = plan.keys().cloned().sorted().zip(weights.into_iter().map(u32::from)).collect();
+        let sum: u32 = weights.values().sum();
+        prop_assume!(sum > 0);
+
+        let original = plan.clone();
+        let changes = rebalance_to_weights(&mut plan, &weights);
+
+        let total = original.values().map(|orders| orders.len()).sum::<usize>();
+        for (rider_id, orders) in &plan {
+            let weight = weights.get(rider_id).cloned().unwrap_or(0) as usize;
+            assert!((orders.len() * sum as usize).abs_diff(total * weight) < sum as usize);
+        }
+        assert_eq!(plan.values().flatten().sorted().collect::<Vec<_>>(), original.values().flatten().sorted().collect::<Vec<_>>());
+        let mut replayed = original;
+        apply_changes(&mut replayed, &changes);
+        assert_eq!(replayed, plan);
+    }
+
+    #[test]
+    fn reindexed_plan_maps_back_to_original(plan in arb_plan()) {
+        let (dense, original_ids) = reindex(&plan);
+        assert!(dense.keys().all(|id| (*id as usize) < plan.len()));
+        let restored: Plan = dense.into_iter().map(|(id, orders)| (original_ids[&id], orders)).collect();
+        assert_eq!(restored, plan);
+        assert!(isomorphic(&reindex(&plan).0, &plan));
+    }
+
+    #[test]
+    fn rider_rejected_v1_and_v2_differ_only_where_documented(
+        plan in arb_plan(),
+        rider_id: u32,
+        order_id: u64,
+        from_plan: Option<(usize, usize)>,
+    ) {
+        // Mostly reject orders the rider really holds, sometimes made up ones
+        let held: Vec<(u32, u64)> = plan.iter().flat_map(|(r, orders)| orders.iter().map(move |o| (*r, *o))).sorted().collect();
+        let (rider_id, order_id) = match from_plan {
+            Some((i, _)) if !held.is_empty() => held[i % held.len()],
+            Some((i, _)) if !plan.is_empty() => (*plan.keys().sorted().nth(i % plan.len()).unwrap(), order_id),
+            _ => (rider_id, order_id),
+        };
+        let event = Event::RiderRejected { rider_id, order_id };
+        let (v1, _) = process_event_v1(plan.clone(), event);
+        let (v2, _) = process_event_v2(plan.clone(), event);
+
+        let copies = plan.get(&rider_id).map_or(0, |orders| orders.iter().filter(|v| **v == order_id).count());
+        let other_rider = plan.keys().find(|id| **id != rider_id).cloned();
+        let mut expected_v1 = plan.clone();
+        if let Some(orders) = expected_v1.get_mut(&rider_id) {
+            orders.retain(|v| *v != order_id);
+        }
+        if let Some(to) = other_rider {
+            expected_v1.get_mut(&to).unwrap().push(order_id);
+        }
+        assert_eq!(&v1, &expected_v1);
+        let least_loaded = plan
+            .iter()
+            .filter(|(id, _)| **id != rider_id)
+            .map(|(id, orders)| (orders.len(), *id))
+            .min()
+            .map(|(_, id)| id);
+        match (copies, least_loaded) {
+            // The order wasn't there: v2 ignores it, v1 invents it on another rider
+            (0, _) => assert_eq!(&v2, &plan),
+            // Lone rider: v2 keeps the order, v1 loses it
+            (_, None) => assert_eq!(&v2, &plan),
+            // v2 moves one copy to the least-loaded rider, v1 all of them to
+            // the first other one; with a single copy the same orders stay planned
+            (n, Some(to)) => {
+                assert_eq!(v2[&rider_id].iter().filter(|v| **v == order_id).count(), n - 1);
+                assert_eq!(v2[&to].len(), plan[&to].len() + 1);
+                assert_eq!(v2[&to].last(), Some(&order_id));
+                if n == 1 {
+                    let planned = |plan: &Plan| plan.values().flatten().cloned().sorted().collect::<Vec<_>>();
+                    assert_eq!(planned(&v2), planned(&v1));
+                }
+            }
+        }
+    }
 
     #[test]
-    fn orders_are_assigned_in_an_even_way(riders: Vec<Rider>, orders: Vec<Order>) {
+    fn ignored_rejections_leave_the_plan_exactly_as_it_was(
+        mut plan in arb_plan(),
+        lone_rider: bool,
+        rider_id: u32,
+        order_id: u64,
+        from_plan: Option<usize>,
+    ) {
+        if lone_rider {
+            let lowest = plan.keys().min().cloned();
+            plan.retain(|id, _| Some(*id) == lowest);
+        }
+        let held: Vec<(u32, u64)> = plan.iter().flat_map(|(r, orders)| orders.iter().map(move |o| (*r, *o))).sorted().collect();
+        let (rider_id, order_id) = match from_plan {
+            Some(i) if !held.is_empty() => held[i % held.len()],
+            _ => (rider_id, order_id),
+        };
+
+        let (after, outcome) = process_event(plan.clone(), Event::RiderRejected { rider_id, order_id });
+        if let EventOutcome::Ignored { .. } = outcome {
+            #[cfg(feature = "bincode")]
+            assert_eq!(plan_to_bytes(&after), plan_to_bytes(&plan));
+            assert_eq!(after, plan);
+        }
+    }
+
+    #[test]
+    fn lone_rider_keeps_rejected_order(rider_id: u32, orders in prop::collection::vec(any::<u64>(), 1..20), which: usize) {
+        let plan = Plan::from_iter([(rider_id, orders.clone())]);
+        let order_id = orders[which % orders.len()];
+
+        let (after, outcome) = process_event(plan.clone(), Event::RiderRejected { rider_id, order_id });
+        assert_eq!(outcome, ignored(IgnoreReason::NoOtherRider));
+        assert!(after[&rider_id].contains(&order_id));
+        assert_eq!(after, plan);
+    }
+
+    #[test]
+    fn other_events_match_between_v1_and_v2(event in arb_event_for(&fuzz_plan())) {
+        prop_assume!(!matches!(event, Event::RiderRejected { .. }));
+        assert_eq!(process_event_v1(fuzz_plan(), event), process_event_v2(fuzz_plan(), event));
+    }
+
+    #[test]
+    fn tiered_plan_balances_each_priority_tier(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let plan = compute_plan_tiered(&riders, &orders);
+        let priorities: HashMap<u64, u8> = orders.iter().map(|o| (o.id, o.priority)).collect();
+        for priority in orders.iter().map(|o| o.priority).sorted().dedup() {
+            let (min, max) = riders.iter()
+                .map(|r| plan.get(&r.id).map_or(0, |assigned| assigned.iter().filter(|o| priorities[o] == priority).count()))
+                .minmax().into_option().unwrap();
+            assert!(max - min <= 1);
+        }
+        // Queues start with the most urgent orders
+        for assigned in plan.values() {
+            assert!(assigned.iter().map(|o| std::cmp::Reverse(priorities[o])).is_sorted());
+        }
+    }
+
+    #[test]
+    fn sorting_by_id_ignores_input_order(
+        riders: Vec<Rider>,
+        (orders, shuffled) in any::<Vec<Order>>().prop_flat_map(|orders| (Just(orders.clone()), Just(orders).prop_shuffle())),
+    ) {
+        assert_eq!(
+            compute_plan_sorted(&riders, &shuffled, OrderSort::ById),
+            compute_plan_sorted(&riders, &orders, OrderSort::ById)
+        );
+
+        // As given, the first rider starts with whichever order came first
+        prop_assume!(riders.len() >= 2 && orders.len() >= 2);
+        prop_assume!(riders.iter().map(|r| r.id).all_unique() && orders.iter().map(|o| o.id).all_unique());
+        let reversed: Vec<Order> = orders.iter().rev().cloned().collect();
+        assert_ne!(
+            compute_plan_sorted(&riders, &reversed, OrderSort::AsGiven),
+            compute_plan_sorted(&riders, &orders, OrderSort::AsGiven)
+        );
+    }
+
+    #[test]
+    fn observer_sees_every_assignment_once(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let mut seen = vec![];
+        let plan = compute_plan_observed(&riders, &orders, &mut |order_id, rider_id| seen.push((order_id, rider_id)));
+        assert_eq!(plan, compute_plan(&riders, &orders));
+        assert!(seen.iter().map(|(order_id, _)| order_id).all_unique());
+        let mut observed = Plan::default();
+        for (order_id, rider_id) in seen {
+            observed.entry(rider_id).or_default().push(order_id);
+        }
+        assert_eq!(observed, plan);
+    }
+
+    #[test]
+    fn rate_limited_processing_ends_at_the_bulk_replay(
+        events in prop::collection::vec(arb_event_for(&fuzz_plan()), 1..30),
+        max_per_tick in 1..5usize,
+        cooldown in 0..3u64,
+    ) {
+        let start = fuzz_plan();
+        let clock = MoveClock::new(cooldown);
+        let snapshots = process_events_rate_limited(start.clone(), &events, max_per_tick, &mut clock.clone());
+        assert_eq!(snapshots.len(), events.len().div_ceil(max_per_tick));
+        assert_eq!(snapshots.last(), Some(&replay(start.clone(), &events, &mut clock.clone())));
+
+        let one_by_one = process_events_rate_limited(start.clone(), &events, 1, &mut clock.clone());
+        assert_eq!(one_by_one.len(), events.len());
+        assert_eq!(one_by_one.last(), Some(&replay(start, &events, &mut clock.clone())));
+    }
+
+    #[test]
+    fn lww_merge_is_commutative_and_idempotent(
+        a in prop::collection::hash_map(0..8u32, prop::collection::vec(0..16u64, 0..5), 0..5),
+        b in prop::collection::hash_map(0..8u32, prop::collection::vec(0..16u64, 0..5), 0..5),
+        ts_a in prop::collection::hash_map(0..16u64, 0..4u64, 0..16),
+        ts_b in prop::collection::hash_map(0..16u64, 0..4u64, 0..16),
+    ) {
+        let (a, b): (Plan, Plan) = (a.into_iter().collect(), b.into_iter().collect());
+        let merged = merge_lww(a.clone(), b.clone(), &ts_a, &ts_b);
+        assert_eq!(merge_lww(b.clone(), a.clone(), &ts_b, &ts_a), merged.clone());
+        assert!(merged.values().flatten().all_unique());
+
+        // The merged plan carries the later timestamp of the sides holding
+        // each order
+        let mut ts_merged: HashMap<u64, u64> = HashMap::new();
+        for (plan, ts) in [(&a, &ts_a), (&b, &ts_b)] {
+            for order_id in plan.values().flatten() {
+                let t = ts.get(order_id).copied().unwrap_or(0);
+                let merged_ts = ts_merged.entry(*order_id).or_insert(t);
+                *merged_ts = (*merged_ts).max(t);
+            }
+        }
+        assert_eq!(merge_lww(merged.clone(), merged.clone(), &ts_merged, &ts_merged), merged.clone());
+        assert_eq!(merge_lww(merged.clone(), b, &ts_merged, &ts_b), merged);
+    }
+
+    #[test]
+    fn rebalance_is_a_fixpoint(plan in arb_plan()) {
+        let mut once = plan.clone();
+        let changes = rebalance(&mut once);
+        assert!(load_spread(&once) <= 1);
+
+        let mut twice = once.clone();
+        assert_eq!(rebalance(&mut twice), vec![]);
+        assert_eq!(&twice, &once);
+        assert_eq!(diff_plans(&once, &twice), vec![]);
+        if load_spread(&plan) <= 1 {
+            assert_eq!(changes, vec![]);
+            assert_eq!(diff_plans(&plan, &once), vec![]);
+        }
+    }
+
+    #[test]
+    fn sorted_lines_round_trip(plan in arb_plan()) {
+        let lines = plan_to_sorted_lines(&plan);
+        let parsed = plan_from_sorted_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        let sorted: Plan = plan
+            .into_iter()
+            .map(|(rider_id, orders)| (rider_id, orders.into_iter().sorted().collect()))
+            .collect();
+        assert_eq!(parsed, Ok(sorted));
+    }
+
+    #[test]
+    fn fast_replay_matches_full_replay(
+        (start, events) in arb_plan()
+            .prop_filter("needs a plan with orders", |plan| plan.values().any(|orders| !orders.is_empty()))
+            .prop_flat_map(|plan| {
+                let events = prop::collection::vec(arb_event_for(&plan), 0..40);
+                (Just(plan), events)
+            }),
+        cooldown in 0..3u64,
+    ) {
+        let (mut fast_clock, mut clock) = (MoveClock::new(cooldown), MoveClock::new(cooldown));
+        assert_eq!(replay_fast(start.clone(), &events, &mut fast_clock), replay(start, &events, &mut clock));
+        assert_eq!(fast_clock.step, clock.step);
+    }
+
+    #[test]
+    fn coalesced_replay_matches_full_replay(
+        (start, events) in arb_plan()
+            .prop_filter("needs a valid plan with orders", |plan| {
+                plan.values().flatten().all_unique() && plan.values().any(|orders| !orders.is_empty())
+            })
+            .prop_flat_map(|plan| {
+                let events = prop::collection::vec(arb_event_for(&plan), 0..30);
+                (Just(plan), events)
+            })
+    ) {
+        let coalesced = coalesce(&events);
+        assert!(coalesced.len() <= events.len());
+        assert!(plans_equivalent(&replay(start.clone(), &coalesced, &mut MoveClock::default()), &replay(start, &events, &mut MoveClock::default())));
+    }
+
+    #[test]
+    fn orders_only_go_to_riders_on_shift(riders: Vec<Rider>, orders: Vec<Order>, now in 0..100u64) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+
+        let (plan, leftovers) = compute_plan_at(&riders, &orders, now);
+        let on_shift: HashSet<u32> = riders.iter().filter(|r| r.on_shift(now)).map(|r| r.id).collect();
+        assert!(plan.keys().all(|rider_id| on_shift.contains(rider_id)));
+        assert_eq!(plan.values().map(|o| o.len()).sum::<usize>() + leftovers.len(), orders.len());
+
+        let after_every_shift = riders.iter().map(|r| r.shift_end).max().unwrap_or(0);
+        let (plan, leftovers) = compute_plan_at(&riders, &orders, after_every_shift);
+        assert!(plan.is_empty());
+        assert_eq!(leftovers, orders.iter().map(|o| o.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn assign_with_by_count_picks_least_loaded(mut plan in arb_plan(), order_id: u64) {
+        prop_assume!(!plan.is_empty());
+
+        let min_load = plan.values().map(|orders| orders.len()).min().unwrap();
+        let expected = plan.iter().filter(|(_, orders)| orders.len() == min_load).map(|(id, _)| *id).min();
+        let chosen = assign_with(&mut plan, order_id, by_count);
+        assert_eq!(chosen, expected);
+        assert_eq!(plan[&chosen.unwrap()].last(), Some(&order_id));
+    }
+
+    #[test]
+    fn plan_json_round_trip(plan in arb_plan()) {
+        let text = plan_to_json(&plan).to_string();
+        assert_eq!(plan_from_json(&json::Json::parse(&text).unwrap()), Some(plan));
+    }
+
+    #[test]
+    fn fresh_orders_reuse_the_template_riders(old_plan in arb_plan(), new_orders: Vec<Order>) {
+        prop_assume!(!old_plan.is_empty());
+
+        let plan = reassign_fresh(&template_from(&old_plan), &new_orders);
+        assert_eq!(plan.keys().sorted().collect::<Vec<_>>(), old_plan.keys().sorted().collect::<Vec<_>>());
+        assert_eq!(
+            plan.values().flatten().sorted().collect::<Vec<_>>(),
+            new_orders.iter().map(|o| &o.id).sorted().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn cancel_where_removes_exactly_the_matches(mut plan in arb_plan()) {
+        prop_assume!(plan.values().flatten().all_unique());
+
+        let original = plan.clone();
+        let canceled = cancel_where(&mut plan, |id| id % 2 == 0);
+
+        let expected: Vec<u64> = manifest(&original).into_iter().flat_map(|(_, orders)| orders).filter(|id| id % 2 == 0).collect();
+        assert_eq!(canceled, expected);
+        for (rider_id, orders) in &original {
+            let kept: Vec<u64> = orders.iter().cloned().filter(|id| id % 2 == 1).collect();
+            assert_eq!(plan[rider_id], kept);
+        }
+        assert_eq!(plan.len(), original.len());
+
+        // Under the prune policy only the riders the cancel emptied go away
+        let emptied: Vec<PlanViolation> = manifest(&plan)
+            .into_iter()
+            .filter(|(_, orders)| orders.is_empty())
+            .map(|(rider_id, _)| PlanViolation::EmptyRider { rider_id })
+            .collect();
+        let fixed = repair_plan_with(&mut plan, EmptyRiderPolicy::Prune);
+        assert_eq!(fixed.into_iter().filter(|v| matches!(v, PlanViolation::EmptyRider { .. })).collect::<Vec<_>>(), emptied);
+        assert!(plan.values().all(|orders| !orders.is_empty()));
+    }
+
+    #[test]
+    fn order_canceled_clears_the_order_everywhere(plan in arb_plan(), pick: usize) {
+        prop_assume!(validate_plan(&plan).is_ok());
+        let held: Vec<u64> = plan.values().flatten().cloned().sorted().collect();
+        prop_assume!(!held.is_empty());
+
+        let order_id = held[pick % held.len()];
+        let (after, outcome) = process_event(plan, Event::OrderCanceled { order_id });
+        assert_eq!(outcome, EventOutcome::Canceled { order_id, moves: vec![] });
+        assert!(after.values().all(|orders| !orders.contains(&order_id)));
+        assert_eq!(
+            after.values().flatten().cloned().sorted().collect::<Vec<_>>(),
+            held.into_iter().filter(|id| *id != order_id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn plan_to_events_reaches_the_target(
+        // Kept small, the replay is quadratic in the plan size
+        current in proptest::collection::hash_map(any::<u32>(), proptest::collection::vec(any::<u64>(), 0..20), 0..20)
+            .prop_map(|plan| plan.into_iter().collect::<Plan>()),
+        target_riders: Vec<u32>,
+        seed: u64,
+    ) {
+        prop_assume!(current.values().flatten().all_unique());
+        prop_assume!(!target_riders.is_empty());
+
+        // The same orders dealt out over other riders
+        let mut target = Plan::default();
+        for order_id in current.values().flatten() {
+            let rider_id = target_riders[(mix64(order_id ^ seed) % target_riders.len() as u64) as usize];
+            target.entry(rider_id).or_default().push(*order_id);
+        }
+        let events = plan_to_events(&current, &target);
+        assert!(plans_equivalent(&replay(current.clone(), &events, &mut MoveClock::default()), &target));
+        assert_eq!(plan_to_events(&current, &current), vec![]);
+        assert_eq!(plan_to_events(&target, &target), vec![]);
+    }
+
+    #[test]
+    fn scenario_round_trip(riders: Vec<Rider>, orders: Vec<Order>) {
+        let plan = compute_plan(&riders, &orders);
+        let text = scenario::export_scenario(&riders, &orders, &plan);
+        let (riders_back, orders_back, plan_back) = scenario::import_scenario(&text).unwrap();
+        // Neither type is PartialEq, f64 fields and all
+        assert_eq!(format!("{riders_back:?}"), format!("{riders:?}"));
+        assert_eq!(format!("{orders_back:?}"), format!("{orders:?}"));
+        assert_eq!(&plan_back, &plan);
+        assert_eq!(compute_plan(&riders_back, &orders_back), plan_back);
+    }
+
+    #[test]
+    fn checksum_tracks_assignments_only(mut plan in arb_plan(), empty_riders: Vec<u32>, pick: (usize, usize)) {
+        prop_assume!(plan.values().flatten().all_unique());
+
+        let mut equivalent = plan.clone();
+        for orders in equivalent.values_mut() {
+            orders.reverse();
+        }
+        for rider_id in empty_riders {
+            equivalent.entry(rider_id).or_default();
+        }
+        assert!(plans_equivalent(&plan, &equivalent));
+        assert_eq!(plan_checksum(&plan), plan_checksum(&equivalent));
+
+        let held: Vec<(u32, u64)> = plan.iter().flat_map(|(r, orders)| orders.iter().map(move |o| (*r, *o))).sorted().collect();
+        let riders: Vec<u32> = plan.keys().cloned().sorted().collect();
+        prop_assume!(!held.is_empty() && riders.len() > 1);
+        let (from, order_id) = held[pick.0 % held.len()];
+        let to = riders.iter().filter(|id| **id != from).nth(pick.1 % (riders.len() - 1)).cloned().unwrap();
+        let before = plan_checksum(&plan);
+        apply_changes(&mut plan, &[PlanChange::Moved { order_id, from, to }]);
+        assert_ne!(plan_checksum(&plan), before);
+    }
+
+    #[test]
+    fn event_log_round_trip(events: Vec<Event>) {
+        assert_eq!(parse_event_log(&event_log(&events)), Ok(events));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn plan_bytes_round_trip(plan in arb_plan()) {
+        assert_eq!(plan_from_bytes(&plan_to_bytes(&plan)), Ok(plan));
+    }
+
+    #[test]
+    fn compared_strategies_assign_all_orders(
+        riders in prop::collection::vec(arb_located_rider(), 1..20),
+        orders in prop::collection::vec(arb_located_order(), 0..100),
+    ) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let strategies: [&dyn AssignmentStrategy; 3] = [&RoundRobinStrategy, &LeastLoadedStrategy, &NearestStrategy];
+        for strategy in strategies {
+            let (plan, leftovers) = strategy.assign(&riders, &orders);
+            assert!(leftovers.is_empty(), "{} left {:?}", strategy.name(), leftovers);
+            let assigned: HashSet<u64> = plan.values().flatten().cloned().collect();
+            assert_eq!(assigned, orders.iter().map(|o| o.id).collect(), "{}", strategy.name());
+        }
+        for report in compare_strategies(&strategies, &riders, &orders) {
+            assert_eq!(report.leftovers, 0);
+            assert!(report.total_distance_km.is_some());
+        }
+    }
+
+    #[test]
+    fn uniform_traffic_keeps_nearest_assignment(
+        riders in prop::collection::vec(arb_located_rider(), 1..20),
+        orders in prop::collection::vec(arb_located_order(), 0..100),
+    ) {
+        // Doubling is exact, so no near-tie can flip
+        assert_eq!(compute_plan_nearest_by(&riders, &orders, |_, _| 2.0), compute_plan_nearest(&riders, &orders));
+    }
+
+    #[test]
+    fn clusters_partition_the_orders(
+        riders in prop::collection::vec(arb_located_rider(), 1..20),
+        orders in prop::collection::vec(prop_oneof![arb_located_order(), any::<Order>()], 0..100),
+        k in 0..10usize,
+    ) {
+        let clusters = cluster_orders(&orders, k);
+        assert!(clusters.len() <= k.max(1) + 1);
+        assert!(clusters.iter().all(|cluster| !cluster.is_empty()));
+        let ids = || orders.iter().map(|o| o.id).sorted().collect::<Vec<_>>();
+        assert_eq!(clusters.iter().flatten().cloned().sorted().collect::<Vec<_>>(), ids());
+
+        let plan = compute_plan_clustered(&riders, &orders, k);
+        assert_eq!(plan.values().flatten().cloned().sorted().collect::<Vec<_>>(), ids());
+    }
+
+    #[test]
+    fn scoring_generalizes_least_loaded_and_nearest(
+        riders in prop::collection::vec(arb_located_rider(), 1..20),
+        orders in prop::collection::vec(arb_located_order(), 0..100),
+        rating in prop::collection::vec(0.0..5.0f64, 20),
+    ) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        let riders: Vec<Rider> = riders.into_iter().zip(rating).map(|(r, rating)| Rider { rating, ..r }).collect();
+
+        let by_load = ScoreWeights { load: 1.0, ..Default::default() };
+        assert_eq!(compute_plan_scored(&riders, &orders, &by_load), compute_plan_least_loaded(&riders, &orders).0);
+        let by_distance = ScoreWeights { distance: 1.0, ..Default::default() };
+        assert_eq!(compute_plan_scored(&riders, &orders, &by_distance), compute_plan_nearest(&riders, &orders).0);
+    }
+
+    #[test]
+    fn every_strategy_assigns_all_orders_when_capacity_suffices(
+        riders in prop::collection::vec(arb_located_rider(), 1..20),
+        orders in prop::collection::vec(arb_located_order(), 0..100),
+    ) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+        // Enough room in total, but not enough for nearest-only placement
+        let capacity = orders.len().div_ceil(riders.len()) as u32;
+        let riders: Vec<Rider> = riders.into_iter().map(|r| Rider { capacity: Some(capacity), ..r }).collect();
+
+        for strategy in Strategy::ALL {
+            let (plan, leftovers) = compute_plan_with(strategy, &riders, &orders);
+            let assigned: HashSet<u64> = plan.values().flatten().cloned().collect();
+            assert_eq!(assigned, orders.iter().map(|o| o.id).collect(), "{:?}", strategy);
+            assert!(leftovers.is_empty(), "{:?}", strategy);
+            if strategy != Strategy::Nearest {
+                assert!(plan.values().all(|o| o.len() <= capacity as usize), "{:?}", strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn balanced_operations_keep_spread_within_one(riders: Vec<Rider>, orders: Vec<Order>, adds_rider: Vec<bool>) {
         prop_assume!(!riders.is_empty());
         prop_assume!(riders.len() <= orders.len());
         prop_assume!(riders.iter().map(|r| r.id).all_unique());
         prop_assume!(orders.iter().map(|o| o.id).all_unique());
 
-        let plan = compute_plan(&riders, &orders);
-        let (min_orders, max_orders) = plan.values().map(|orders| orders.len()).minmax().into_option().unwrap();
-        assert!(1 >= max_orders-min_orders, "min: {}, max: {}", min_orders, max_orders);
+        // Only least-loaded additions and rebalancing riders, no cancels or rejects
+        let start = compute_plan(&riders, &orders);
+        let next_order = orders.iter().map(|o| o.id).max().unwrap();
+        let next_rider = riders.iter().map(|r| r.id).max().unwrap();
+        let events: Vec<Event> = adds_rider.iter().enumerate().map(|(i, adds_rider)| {
+            if *adds_rider {
+                Event::RiderAdded { rider_id: next_rider.wrapping_add(1 + i as u32) }
+            } else {
+                Event::OrderAdded { order_id: next_order.wrapping_add(1 + i as u64) }
+            }
+        }).collect();
+        prop_assume!(events.iter().all(|e| match e {
+            Event::RiderAdded { rider_id } => !start.contains_key(rider_id),
+            Event::OrderAdded { order_id } => !orders.iter().any(|o| o.id == *order_id),
+            _ => true,
+        }));
+
+        assert!(max_observed_spread(&replay_with_snapshots(start, &events)) <= 1);
+    }
+
+    #[test]
+    fn capped_round_robin_only_differs_by_respecting_caps(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let (plan, leftovers) = compute_plan_capped_rr(&riders, &orders);
+        let load = |id: u32| plan.get(&id).map_or(0, |o| o.len());
+        for rider in &riders {
+            assert!(rider.capacity.is_none_or(|c| load(rider.id) <= c as usize));
+        }
+        if !leftovers.is_empty() {
+            assert!(riders.iter().all(|r| !r.has_room(load(r.id))));
+        }
+        let assigned: Vec<u64> = plan.values().flatten().chain(leftovers.iter()).cloned().sorted().collect();
+        assert_eq!(assigned, orders.iter().map(|o| o.id).sorted().collect::<Vec<_>>());
+
+        // compute_plan never returns without riders
+        prop_assume!(!riders.is_empty());
+        let uncapped: Vec<Rider> = riders.into_iter().map(|r| Rider { capacity: None, ..r }).collect();
+        assert_eq!(compute_plan_capped_rr(&uncapped, &orders), (compute_plan(&uncapped, &orders), vec![]));
+    }
+
+    #[test]
+    fn orders_are_assigned_in_an_even_way_under_capacity(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        // Full riders sit exactly at their cap, the rest share evenly
+        let (plan, _) = compute_plan_capped_rr(&riders, &orders);
+        let load = |id: u32| plan.get(&id).map_or(0, |o| o.len());
+        let (full, not_full): (Vec<&Rider>, Vec<&Rider>) = riders.iter().partition(|r| !r.has_room(load(r.id)));
+        for rider in full {
+            assert_eq!(Some(load(rider.id)), rider.capacity.map(|c| c as usize));
+        }
+        if let Some((min_orders, max_orders)) = not_full.iter().map(|r| load(r.id)).minmax().into_option() {
+            assert!(1 >= max_orders-min_orders, "min: {}, max: {}", min_orders, max_orders);
+        }
+    }
+
+    #[test]
+    fn min_riders_uses_as_few_riders_as_capacity_allows(riders: Vec<Rider>, orders: Vec<Order>, capacity in 1..10u32) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(riders.len() * capacity as usize >= orders.len());
+
+        let riders: Vec<Rider> = riders.into_iter().map(|r| Rider { capacity: Some(capacity), ..r }).collect();
+        let (plan, unused) = compute_plan_min_riders(&riders, &orders);
+        assert_eq!(plan.len(), orders.len().div_ceil(capacity as usize));
+        assert_eq!(plan.len() + unused.len(), riders.len());
+        assert_eq!(plan.values().map(|o| o.len()).sum::<usize>(), orders.len());
+    }
+
+    #[test]
+    fn added_orders_keep_plan_balanced_and_placements_fixed(riders: Vec<Rider>, orders: Vec<Order>, new_orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+
+        let mut plan = compute_plan(&riders, &orders);
+        for rider in &riders {
+            plan.entry(rider.id).or_default();
+        }
+        let before = plan.clone();
+        add_orders(&mut plan, &new_orders);
+
+        assert!(load_spread(&plan) <= 1);
+        for (rider_id, orders) in &before {
+            assert!(plan[rider_id].starts_with(orders));
+        }
+        let all_orders = orders.iter().chain(&new_orders).map(|o| o.id).sorted().collect::<Vec<_>>();
+        assert_eq!(plan.values().flatten().cloned().sorted().collect::<Vec<_>>(), all_orders);
+    }
+
+    #[test]
+    fn manifest_lists_everything_once_by_rider_id(riders: Vec<Rider>, orders: Vec<Order>) {
+        prop_assume!(!riders.is_empty());
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let plan = compute_plan(&riders, &orders);
+        let manifest = manifest(&plan);
+        assert!(manifest.iter().tuple_windows().all(|(a, b)| a.0 < b.0));
+        assert_eq!(manifest.iter().map(|(id, _)| *id).collect::<HashSet<_>>(), plan.keys().cloned().collect());
+        let listed: Vec<u64> = manifest.iter().flat_map(|(_, orders)| orders.clone()).sorted().collect();
+        assert_eq!(listed, orders.iter().map(|o| o.id).sorted().collect::<Vec<_>>());
+        for (rider_id, queue) in &manifest {
+            assert_eq!(queue, &plan[rider_id]);
+        }
+    }
+
+    #[test]
+    fn validate_delta_agrees_with_validate_plan(starting_plan in arb_plan(), test_events: Vec<TestEvent>) {
+        prop_assume!(starting_plan.len() > 1);
+        prop_assume!(starting_plan.values().all(|orders| !orders.is_empty()));
+        prop_assume!(starting_plan.values().flatten().all_unique());
+
+        let mut plan = starting_plan.clone();
+        for test_event in test_events {
+            let (next, outcome) = process_event(plan, test_event.into_event(&starting_plan));
+            plan = next;
+            assert_eq!(validate_delta(&plan, &outcome), Ok(()), "{:?}", outcome);
+        }
+        assert_eq!(validate_plan(&plan), Ok(()));
+    }
+
+    #[test]
+    fn skill_assignment_respects_complexity(riders: Vec<Rider>, orders: Vec<Order>, raise_by: u8) {
+        prop_assume!(riders.iter().map(|r| r.id).all_unique());
+        prop_assume!(orders.iter().map(|o| o.id).all_unique());
+
+        let skills: HashMap<u32, u8> = riders.iter().map(|r| (r.id, r.skill)).collect();
+        let complexities: HashMap<u64, u8> = orders.iter().map(|o| (o.id, o.complexity)).collect();
+        let (plan, leftovers) = compute_plan_skill(&riders, &orders);
+        for (rider_id, assigned) in &plan {
+            assert!(assigned.iter().all(|order_id| skills[rider_id] >= complexities[order_id]));
+        }
+        let max_skill = riders.iter().map(|r| r.skill).max();
+        assert!(leftovers.iter().all(|order_id| max_skill < Some(complexities[order_id])));
+
+        let harder: Vec<Order> = orders.iter().map(|o| Order { complexity: o.complexity.saturating_add(raise_by), ..o.clone() }).collect();
+        let (_, harder_leftovers) = compute_plan_skill(&riders, &harder);
+        assert!(harder_leftovers.len() >= leftovers.len());
+    }
+
+    #[test]
+    fn repair_plan_makes_plan_valid(mut plan in arb_plan()) {
+        let orders_before: HashSet<u64> = plan.values().flatten().cloned().collect();
+        let was_valid = validate_plan(&plan).is_ok();
+        let original = plan.clone();
+
+        let fixed = repair_plan(&mut plan);
+        assert_eq!(validate_plan(&plan), Ok(()));
+        assert_eq!(plan.values().flatten().cloned().collect::<HashSet<_>>(), orders_before);
+        if was_valid {
+            assert!(fixed.is_empty());
+            assert_eq!(plan, original);
+        }
+    }
+}
+
+#[test]
+fn assign_with_by_weight_picks_lightest_rider() {
+    let orders: Vec<Order> = [(1, 10), (2, 1), (3, 1), (4, 5)]
+        .into_iter()
+        .map(|(id, weight)| Order {
+            id,
+            weight,
+            ..Default::default()
+        })
+        .collect();
+    let mut plan = Plan::from_iter([(0, vec![1]), (1, vec![2, 3])]);
+
+    // By count rider 0 is lighter, by weight rider 1 is
+    assert_eq!(assign_with(&mut plan.clone(), 4, by_count), Some(0));
+    assert_eq!(assign_with(&mut plan, 4, by_weight(&orders)), Some(1));
+    assert_eq!(plan[&1], vec![2, 3, 4]);
+}
+
+#[test]
+fn order_added_bundles_with_same_address() {
+    let riders = vec![
+        Rider {
+            id: 1,
+            capacity: Some(3),
+            ..Default::default()
+        },
+        Rider {
+            id: 2,
+            capacity: Some(3),
+            ..Default::default()
+        },
+    ];
+    let first = Order {
+        id: 10,
+        address_id: Some(7),
+        ..Default::default()
+    };
+    let mut dispatcher = Dispatcher::new(&riders, &[first]);
+    assert_eq!(dispatcher.plan[&1], vec![10]);
+
+    // Rider 2 is idle, but rider 1 already serves address 7
+    let second = Order {
+        id: 11,
+        address_id: Some(7),
+        ..Default::default()
+    };
+    assert_eq!(dispatcher.add_order(second), Some(1));
+    assert_eq!(dispatcher.plan[&1], vec![10, 11]);
+
+    // Unrelated orders still go to the least-loaded rider
+    let other = Order {
+        id: 12,
+        address_id: Some(8),
+        ..Default::default()
+    };
+    assert_eq!(dispatcher.add_order(other), Some(2));
+}
+
+#[test]
+fn order_added_falls_back_when_bundling_rider_is_full() {
+    let riders = vec![
+        Rider {
+            id: 1,
+            capacity: Some(1),
+            ..Default::default()
+        },
+        Rider {
+            id: 2,
+            capacity: Some(1),
+            ..Default::default()
+        },
+    ];
+    let first = Order {
+        id: 10,
+        address_id: Some(7),
+        ..Default::default()
+    };
+    let mut dispatcher = Dispatcher::new(&riders, &[first]);
+
+    let second = Order {
+        id: 11,
+        address_id: Some(7),
+        ..Default::default()
+    };
+    assert_eq!(dispatcher.add_order(second), Some(2));
+    assert_eq!(
+        dispatcher.add_order(Order {
+            id: 12,
+            ..Default::default()
+        }),
+        None
+    );
+}
+
+#[test]
+fn assign_with_on_empty_plan_assigns_nothing() {
+    let mut plan = Plan::default();
+    assert_eq!(assign_with(&mut plan, 1, by_count), None);
+    assert_eq!(assign_one(&mut plan, 1), None);
+    assert!(plan.is_empty());
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn truncated_plan_bytes_fail_to_decode() {
+    let plan = Plan::from_iter([(1, vec![10, 300]), (70000, vec![u64::MAX])]);
+    let bytes = plan_to_bytes(&plan);
+    for len in 0..bytes.len() {
+        assert!(
+            plan_from_bytes(&bytes[..len]).is_err(),
+            "prefix of {} bytes",
+            len
+        );
+    }
+    assert_eq!(plan_from_bytes(&bytes[..3]), Err(DecodeError::Truncated));
+    assert_eq!(
+        plan_from_bytes(&bytes[..bytes.len() - 1]),
+        Err(DecodeError::Truncated)
+    );
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn plan_bytes_detect_foreign_or_newer_blobs() {
+    let mut bytes = plan_to_bytes(&Plan::from_iter([(1, vec![10])]));
+    bytes[PLAN_MAGIC.len()] = PLAN_FORMAT_VERSION + 1;
+    assert_eq!(
+        plan_from_bytes(&bytes),
+        Err(DecodeError::UnsupportedVersion(PLAN_FORMAT_VERSION + 1))
+    );
+    assert_eq!(plan_from_bytes(b"JSON{}"), Err(DecodeError::BadMagic));
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn plan_bytes_reject_repeated_or_unsorted_riders() {
+    // Two riders, each an id and an empty queue
+    let encode = |first: u8, second: u8| {
+        let mut bytes = PLAN_MAGIC.to_vec();
+        bytes.extend([PLAN_FORMAT_VERSION, 2, first, 0, second, 0]);
+        bytes
+    };
+    assert_eq!(
+        plan_from_bytes(&encode(1, 2)),
+        Ok(Plan::from_iter([(1, vec![]), (2, vec![])]))
+    );
+    assert_eq!(
+        plan_from_bytes(&encode(1, 1)),
+        Err(DecodeError::DuplicateRider(1))
+    );
+    assert_eq!(
+        plan_from_bytes(&encode(2, 1)),
+        Err(DecodeError::DuplicateRider(1))
+    );
+}
+
+#[test]
+fn repair_plan_keeps_duplicates_on_lowest_rider() {
+    let mut plan = Plan::from_iter([(3, vec![1, 2]), (1, vec![2, 4, 4]), (2, vec![5])]);
+    assert_eq!(
+        validate_plan(&plan),
+        Err(PlanViolation::DuplicateOrder {
+            order_id: 2,
+            riders: vec![1, 3]
+        })
+    );
+
+    let fixed = repair_plan(&mut plan);
+    assert_eq!(
+        fixed,
+        vec![
+            PlanViolation::DuplicateOrder {
+                order_id: 2,
+                riders: vec![1, 3]
+            },
+            PlanViolation::DuplicateOrder {
+                order_id: 4,
+                riders: vec![1, 1]
+            },
+        ]
+    );
+    assert_eq!(
+        plan,
+        Plan::from_iter([(3, vec![1]), (1, vec![2, 4]), (2, vec![5])])
+    );
+    assert_eq!(validate_plan(&plan), Ok(()));
+    assert!(repair_plan(&mut plan).is_empty());
+}
+
+#[test]
+fn repair_plan_prunes_empty_riders_on_request() {
+    let mut plan = Plan::from_iter([(1, vec![7]), (2, vec![7]), (3, vec![])]);
+    let mut kept = plan.clone();
+
+    repair_plan(&mut kept);
+    assert_eq!(
+        kept,
+        Plan::from_iter([(1, vec![7]), (2, vec![]), (3, vec![])])
+    );
+
+    let fixed = repair_plan_with(&mut plan, EmptyRiderPolicy::Prune);
+    assert_eq!(plan, Plan::from_iter([(1, vec![7])]));
+    assert!(fixed.contains(&PlanViolation::EmptyRider { rider_id: 2 }));
+    assert!(fixed.contains(&PlanViolation::EmptyRider { rider_id: 3 }));
+}
+
+#[test]
+fn plan_cost_matches_hand_calculation() {
+    let riders = vec![
+        Rider {
+            id: 1,
+            location: Some((0.0, 0.0)),
+            ..Default::default()
+        },
+        Rider {
+            id: 2,
+            location: Some((10.0, 10.0)),
+            ..Default::default()
+        },
+        Rider {
+            id: 3,
+            ..Default::default()
+        },
+    ];
+    let orders: Vec<Order> = [
+        (1, Some((0.0, 1.0))),
+        (2, Some((1.0, 0.0))),
+        (3, Some((10.0, 10.0))),
+        (4, None),
+        (5, Some((0.0, 1.0))),
+    ]
+    .into_iter()
+    .map(|(id, location)| Order {
+        id,
+        location,
+        ..Default::default()
+    })
+    .collect();
+    let plan = Plan::from_iter([(1, vec![1, 2, 4]), (2, vec![3]), (3, vec![5])]);
+
+    // One degree along the equator or a meridian is 6371 * pi / 180 km; order 3
+    // sits on its rider, order 4 has no location and rider 3 has none either
+    let one_degree_km = EARTH_RADIUS_KM * std::f64::consts::PI / 180.0;
+    let cost = plan_cost(&plan, &riders, &orders, 2.5);
+    assert!(
+        (cost - 2.0 * one_degree_km * 2.5).abs() < 1e-9,
+        "cost {}",
+        cost
+    );
+    assert!((one_degree_km - 111.19492664).abs() < 1e-6);
+}
+
+#[test]
+fn strategy_reports_follow_input_order() {
+    let riders: Vec<Rider> = (1..=3)
+        .map(|id| Rider {
+            id,
+            location: Some((0.0, id as f64)),
+            ..Default::default()
+        })
+        .collect();
+    // Every order sits on rider 1, so nearest piles them up there
+    let orders: Vec<Order> = (1..=6)
+        .map(|id| Order {
+            id,
+            location: Some((0.0, 1.0)),
+            ..Default::default()
+        })
+        .collect();
+
+    let reports = compare_strategies(
+        &[&NearestStrategy, &RoundRobinStrategy, &LeastLoadedStrategy],
+        &riders,
+        &orders,
+    );
+    let names: Vec<_> = reports.iter().map(|r| r.name).collect();
+    assert_eq!(names, vec!["nearest", "round-robin", "least-loaded"]);
+
+    assert_eq!((reports[0].min_load, reports[0].max_load), (0, 6));
+    assert_eq!(reports[0].total_distance_km, Some(0.0));
+    assert_eq!((reports[1].min_load, reports[1].max_load), (2, 2));
+    assert!(reports[1].total_distance_km.unwrap() > 0.0);
+
+    let no_locations = vec![Order {
+        id: 1,
+        ..Default::default()
+    }];
+    let reports = compare_strategies(&[&NearestStrategy], &riders, &no_locations);
+    assert_eq!(reports[0].leftovers, 1);
+    assert_eq!(reports[0].total_distance_km, None);
+}
+
+#[test]
+fn orders_without_riders_wait_in_pending_queue() {
+    let mut dispatcher = Dispatcher::default();
+    for order_id in [3, 1, 2] {
+        assert_eq!(dispatcher.assign_one_or_queue(order_id), None);
+    }
+    assert_eq!(dispatcher.pending, vec![3, 1, 2]);
+    assert!(dispatcher.drain_pending().is_empty());
+
+    dispatcher.add_rider(Rider {
+        id: 7,
+        capacity: Some(2),
+        ..Default::default()
+    });
+    assert_eq!(dispatcher.drain_pending(), vec![3, 1]);
+    assert_eq!(dispatcher.plan[&7], vec![3, 1]);
+    assert_eq!(dispatcher.pending, vec![2]);
+
+    // The new rider immediately takes over load before the queue is retried
+    dispatcher.add_rider(Rider {
+        id: 8,
+        ..Default::default()
+    });
+    assert_eq!(dispatcher.plan[&8], vec![1]);
+    assert_eq!(dispatcher.drain_pending(), vec![2]);
+    assert_eq!(dispatcher.plan[&7], vec![3, 2]);
+    assert!(dispatcher.pending.is_empty());
+}
+
+#[test]
+fn rider_added_takes_load_from_busiest_riders() {
+    let plan = Plan::from_iter([
+        (1, vec![10, 11, 12, 13]),
+        (2, vec![20, 21, 22]),
+        (3, vec![30]),
+    ]);
+    let (plan, _) = process_event(plan, Event::RiderAdded { rider_id: 4 });
+    assert_eq!(
+        plan,
+        Plan::from_iter([
+            (1, vec![10, 11]),
+            (2, vec![20, 21]),
+            (3, vec![30, 12]),
+            (4, vec![13, 22])
+        ])
+    );
+}
+
+#[test]
+fn cascade_moves_outward_when_nearest_rider_is_full() {
+    let riders: Vec<Rider> = [(1, 0.0), (2, 1.0), (3, 5.0)]
+        .into_iter()
+        .map(|(id, lon)| Rider {
+            id,
+            capacity: Some(1),
+            location: Some((0.0, lon)),
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (1..=4)
+        .map(|id| Order {
+            id,
+            location: Some((0.0, 0.0)),
+            ..Default::default()
+        })
+        .collect();
+
+    let (plan, leftovers) = compute_plan_with(Strategy::Cascade, &riders, &orders);
+    assert_eq!(
+        plan,
+        Plan::from_iter([(1, vec![1]), (2, vec![2]), (3, vec![3])])
+    );
+    assert_eq!(leftovers, vec![4]);
+
+    let (plan, leftovers) = compute_plan_with(Strategy::Nearest, &riders, &orders);
+    assert_eq!(plan, Plan::from_iter([(1, vec![1, 2, 3, 4])]));
+    assert!(leftovers.is_empty());
+}
+
+#[test]
+fn impossible_orders_land_in_dead_letter_with_reason() {
+    let riders: Vec<Rider> = [(1, 0, 0b01, 3), (2, 0, 0b11, 1), (3, 1, 0b00, 5)]
+        .into_iter()
+        .map(|(id, zone, tags, skill)| Rider {
+            id,
+            capacity: Some(2),
+            zone,
+            tags,
+            skill,
+            ..Default::default()
+        })
+        .collect();
+    // (id, zone, required tags, complexity)
+    let orders: Vec<Order> = [
+        (10, 0, 0b00, 0),
+        (11, 0, 0b10, 0),
+        (12, 2, 0b00, 0),
+        (13, 0, 0b10, 2),
+        (14, 1, 0b00, 4),
+        (15, 1, 0b00, 4),
+        (16, 1, 0b00, 4),
+        (17, 0, 0b100, 0),
+    ]
+    .into_iter()
+    .map(|(id, zone, required_tags, complexity)| Order {
+        id,
+        zone,
+        required_tags,
+        complexity,
+        ..Default::default()
+    })
+    .collect();
+
+    let result = compute_plan_full(&riders, &orders, &Constraints::ALL);
+    assert_eq!(
+        result.plan,
+        Plan::from_iter([(1, vec![10]), (2, vec![11]), (3, vec![14, 15])])
+    );
+    assert_eq!(
+        result.dead_letter,
+        vec![
+            (12, UnassignableReason::NoRiderInZone),
+            (13, UnassignableReason::InsufficientSkill),
+            (16, UnassignableReason::NoCapacity),
+            (17, UnassignableReason::MissingTags),
+        ]
+    );
+
+    // Without constraints every order is placed
+    let result = compute_plan_full(&riders, &orders, &Constraints::default());
+    assert!(result.dead_letter.is_empty());
+    let result = compute_plan_full(&[], &orders, &Constraints::ALL);
+    assert!(result
+        .dead_letter
+        .iter()
+        .all(|(_, reason)| *reason == UnassignableReason::NoRiders));
+}
+
+#[test]
+fn feasibility_reports_the_first_blocking_reason() {
+    let rider = |id, zone, tags, skill, capacity| Rider {
+        id,
+        zone,
+        tags,
+        skill,
+        capacity,
+        ..Default::default()
+    };
+    let order = |id, zone, required_tags, complexity| Order {
+        id,
+        zone,
+        required_tags,
+        complexity,
+        ..Default::default()
+    };
+    let riders = [rider(1, 0, 0b01, 3, Some(2)), rider(2, 1, 0b11, 1, Some(1))];
+    let all = &Constraints::ALL;
+
+    let orders = [order(10, 0, 0b01, 2), order(11, 1, 0b10, 1)];
+    assert_eq!(is_feasible(&riders, &orders, all), Ok(()));
+    assert_eq!(is_feasible(&riders, &[], all), Ok(()));
+    assert_eq!(is_feasible(&[], &[], all), Ok(()));
+
+    assert_eq!(is_feasible(&[], &orders, all), Err(Infeasibility::NoRiders));
+    assert_eq!(
+        is_feasible(&riders, &[order(10, 3, 0, 0), order(11, 2, 0, 0)], all),
+        Err(Infeasibility::ZoneWithoutRiders { zone: 2 })
+    );
+    assert_eq!(
+        is_feasible(&riders, &[order(10, 0, 0b10, 0)], all),
+        Err(Infeasibility::MissingTags { order_id: 10 })
+    );
+    assert_eq!(
+        is_feasible(&riders, &[order(10, 1, 0, 2)], all),
+        Err(Infeasibility::InsufficientSkill { order_id: 10 })
+    );
+    assert_eq!(
+        is_feasible(&riders, &[order(10, 1, 0, 0), order(11, 1, 0, 0)], all),
+        Err(Infeasibility::NotEnoughCapacity {
+            zone: Some(1),
+            orders: 2,
+            capacity: 1
+        })
+    );
+
+    // Each check goes away with its constraint
+    let orders = [
+        order(10, 1, 0b100, 5),
+        order(11, 1, 0, 0),
+        order(12, 1, 0, 0),
+    ];
+    let only_capacity = Constraints {
+        capacity: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        is_feasible(&riders, &vec![orders[1].clone(); 4], &only_capacity),
+        Err(Infeasibility::NotEnoughCapacity {
+            zone: None,
+            orders: 4,
+            capacity: 3
+        })
+    );
+    assert_eq!(is_feasible(&riders, &orders, &only_capacity), Ok(()));
+    assert_eq!(
+        is_feasible(&riders, &orders, &Constraints::default()),
+        Ok(())
+    );
+    // An unlimited rider covers any number of orders
+    let riders = [rider(1, 1, 0, 0, None), rider(2, 1, 0, 0, Some(1))];
+    assert_eq!(
+        is_feasible(&riders, &vec![orders[1].clone(); 9], all),
+        Ok(())
+    );
+}
+
+#[test]
+fn coalesce_drops_superseded_events() {
+    let events = [
+        Event::RiderRejected {
+            rider_id: 1,
+            order_id: 10,
+        },
+        Event::OrderReassigned {
+            order_id: 11,
+            to: 2,
+        },
+        Event::OrderCanceled { order_id: 10 },
+        Event::OrderReassigned {
+            order_id: 11,
+            to: 3,
+        },
+        // Kept, the OrderAdded after it depends on where order 12 is
+        Event::RiderRejected {
+            rider_id: 2,
+            order_id: 12,
+        },
+        Event::OrderAdded { order_id: 13 },
+        Event::OrderCanceled { order_id: 12 },
+    ];
+    assert_eq!(coalesce(&events), events[2..]);
+
+    let start = Plan::from_iter([(1, vec![10, 11]), (2, vec![12]), (3, vec![])]);
+    assert!(plans_equivalent(
+        &replay(start.clone(), &coalesce(&events), &mut MoveClock::default()),
+        &replay(start, &events, &mut MoveClock::default())
+    ));
+}
+
+#[test]
+fn reassignment_round_trips_are_detected() {
+    let reassign = |order_id, to| Event::OrderReassigned { order_id, to };
+    let reject = |rider_id, order_id| Event::RiderRejected { rider_id, order_id };
+
+    // Order 10 goes 1 -> 2 -> 3 -> 1, order 11 only moves on
+    let cyclic = [
+        reject(1, 10),
+        reassign(10, 2),
+        reassign(11, 2),
+        reassign(10, 3),
+        reassign(11, 4),
+        reassign(10, 1),
+    ];
+    assert_eq!(detect_reassignment_cycle(&cyclic), Some(vec![10]));
+
+    let linear = [
+        reject(1, 10),
+        reassign(10, 2),
+        reassign(10, 2),
+        reassign(10, 3),
+        reject(3, 10),
+        reassign(11, 1),
+    ];
+    assert_eq!(detect_reassignment_cycle(&linear), None);
+
+    // A cancel in between makes the return a fresh assignment
+    let restarted = [
+        reassign(10, 1),
+        reassign(10, 2),
+        Event::OrderCanceled { order_id: 10 },
+        Event::OrderAdded { order_id: 10 },
+        reassign(10, 1),
+    ];
+    assert_eq!(detect_reassignment_cycle(&restarted), None);
+    assert_eq!(
+        detect_reassignment_cycle(
+            &restarted[..2]
+                .iter()
+                .chain(&restarted[4..])
+                .cloned()
+                .collect::<Vec<_>>()
+        ),
+        Some(vec![10])
+    );
+}
+
+#[test]
+fn batch_that_does_not_fit_leaves_plan_untouched() {
+    let riders: Vec<Rider> = (1..=3)
+        .map(|id| Rider {
+            id,
+            capacity: Some(2),
+            ..Default::default()
+        })
+        .collect();
+    let mut plan = Plan::from_iter([(1, vec![10, 11]), (2, vec![20])]);
+
+    let before = plan.clone();
+    assert_eq!(
+        assign_transactional(&mut plan, &riders, &[30, 31, 32, 33]),
+        Err(())
+    );
+    assert_eq!(plan, before);
+
+    assert_eq!(
+        assign_transactional(&mut plan, &riders, &[30, 31, 32]),
+        Ok(HashMap::from([(30, 3), (31, 2), (32, 3)]))
+    );
+    assert_eq!(
+        plan,
+        Plan::from_iter([(1, vec![10, 11]), (2, vec![20, 31]), (3, vec![30, 32])])
+    );
+}
+
+#[test]
+fn nearest_idle_skips_full_riders() {
+    let riders: Vec<Rider> = [(1, 0.1), (2, 2.0)]
+        .into_iter()
+        .map(|(id, lon)| Rider {
+            id,
+            capacity: Some(1),
+            location: Some((0.0, lon)),
+            ..Default::default()
+        })
+        .collect();
+    let order = Order {
+        id: 10,
+        location: Some((0.0, 0.0)),
+        ..Default::default()
+    };
+
+    let plan = Plan::from_iter([(1, vec![1])]);
+    assert_eq!(dispatch_nearest_idle(&plan, &riders, &order), Some(2));
+    assert_eq!(
+        dispatch_nearest_idle(&Plan::default(), &riders, &order),
+        Some(1)
+    );
+}
+
+#[test]
+fn nearest_idle_is_none_when_everyone_is_full() {
+    let riders: Vec<Rider> = [(1, 0.1), (2, 2.0)]
+        .into_iter()
+        .map(|(id, lon)| Rider {
+            id,
+            capacity: Some(1),
+            location: Some((0.0, lon)),
+            ..Default::default()
+        })
+        .collect();
+    let order = Order {
+        id: 10,
+        location: Some((0.0, 0.0)),
+        ..Default::default()
+    };
+
+    let plan = Plan::from_iter([(1, vec![1]), (2, vec![2])]);
+    assert_eq!(dispatch_nearest_idle(&plan, &riders, &order), None);
+}
+
+#[test]
+fn prometheus_output_has_a_gauge_per_rider() {
+    let plan = Plan::from_iter([(3, vec![1, 2]), (1, vec![3, 4, 5]), (7, vec![])]);
+    let text = plan_metrics_prometheus(&plan);
+
+    let rider_lines: Vec<&str> = text
+        .lines()
+        .filter(|line| line.starts_with("dispatch_rider_orders{"))
+        .collect();
+    assert_eq!(
+        rider_lines,
+        [
+            r#"dispatch_rider_orders{rider="1"} 3"#,
+            r#"dispatch_rider_orders{rider="3"} 2"#,
+            r#"dispatch_rider_orders{rider="7"} 0"#,
+        ]
+    );
+    let metrics = plan_metrics(&plan);
+    assert!(text
+        .lines()
+        .any(|line| line == format!("dispatch_total_orders {}", metrics.total_orders)));
+    assert!(text
+        .lines()
+        .any(|line| line == format!("dispatch_load_spread {}", metrics.load_spread)));
+    assert_eq!((metrics.total_orders, metrics.load_spread), (5, 3));
+}
+
+#[test]
+fn reclaimed_orphans_land_on_current_riders() {
+    let riders: Vec<Rider> = [1, 2]
+        .into_iter()
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let mut plan = Plan::from_iter([(1, vec![10, 11, 12]), (9, vec![90, 91]), (5, vec![50])]);
+    assert_eq!(orphaned_orders(&plan, &riders), vec![50, 90, 91]);
+
+    let before: Vec<u64> = plan.values().flatten().cloned().sorted().collect();
+    assert_eq!(reclaim_orphans(&mut plan, &riders), vec![50, 90, 91]);
+    assert_eq!(
+        plan.values()
+            .flatten()
+            .cloned()
+            .sorted()
+            .collect::<Vec<_>>(),
+        before
+    );
+    assert!(orphaned_orders(&plan, &riders).is_empty());
+    assert_eq!(
+        plan,
+        Plan::from_iter([(1, vec![10, 11, 12]), (2, vec![50, 90, 91])])
+    );
+}
+
+#[test]
+fn rejections_wait_out_the_cooldown() {
+    let mut dispatcher = Dispatcher {
+        clock: MoveClock::new(2),
+        ..Dispatcher::from_plan(Plan::from_iter([(1, vec![10, 11]), (2, vec![20])]))
+    };
+    let reject = |rider_id| Event::RiderRejected {
+        rider_id,
+        order_id: 10,
+    };
+
+    assert_eq!(
+        dispatcher.apply(reject(1)),
+        EventOutcome::Reassigned {
+            order_id: 10,
+            from: 1,
+            to: 2
+        }
+    );
+    assert_eq!(dispatcher.apply(reject(2)), ignored(IgnoreReason::Cooldown));
+    dispatcher.apply(Event::OrderCanceled { order_id: 11 });
+    assert_eq!(
+        dispatcher.apply(reject(2)),
+        EventOutcome::Reassigned {
+            order_id: 10,
+            from: 2,
+            to: 1
+        }
+    );
+}
+
+#[test]
+fn replay_refuses_back_to_back_rejections() {
+    let start = Plan::from_iter([(1, vec![10, 11]), (2, vec![20])]);
+    let reject = |rider_id| Event::RiderRejected {
+        rider_id,
+        order_id: 10,
+    };
+
+    let mut clock = MoveClock::new(1);
+    let (plan, outcome) = process_event_clocked(start.clone(), reject(1), &mut clock);
+    assert_eq!(
+        outcome,
+        EventOutcome::Reassigned {
+            order_id: 10,
+            from: 1,
+            to: 2
+        }
+    );
+    let (plan, outcome) = process_event_clocked(plan, reject(2), &mut clock);
+    assert_eq!(outcome, ignored(IgnoreReason::Cooldown));
+    assert_eq!(plan, Plan::from_iter([(1, vec![11]), (2, vec![20, 10])]));
+
+    let events = [reject(1), reject(2)];
+    assert_eq!(replay(start.clone(), &events, &mut MoveClock::new(1)), plan);
+    assert_eq!(
+        replay_fast(start.clone(), &events, &mut MoveClock::new(1)),
+        plan
+    );
+    let snapshots = process_events_rate_limited(start.clone(), &events, 1, &mut MoveClock::new(1));
+    assert_eq!(snapshots.last(), Some(&plan));
+    // Without a cooldown the second rejection sends it back
+    assert_eq!(
+        replay(start.clone(), &events, &mut MoveClock::default()),
+        Plan::from_iter([(1, vec![11, 10]), (2, vec![20])])
+    );
+}
+
+#[test]
+fn relabeled_plans_are_isomorphic_reshuffled_ones_are_not() {
+    let plan = Plan::from_iter([(1, vec![10, 11]), (2, vec![20]), (3, vec![])]);
+    let relabeled = Plan::from_iter([(7, vec![]), (8, vec![20]), (9, vec![11, 10])]);
+    assert!(isomorphic(&plan, &relabeled));
+
+    let reshuffled = Plan::from_iter([(1, vec![10]), (2, vec![11, 20]), (3, vec![])]);
+    assert!(!isomorphic(&plan, &reshuffled));
+    let fewer_riders = Plan::from_iter([(1, vec![10, 11]), (2, vec![20])]);
+    assert!(!isomorphic(&plan, &fewer_riders));
+}
+
+#[test]
+fn orders_follow_their_strongest_affinity() {
+    let riders: Vec<Rider> = [(1, Some(1)), (2, None), (3, None)]
+        .into_iter()
+        .map(|(id, capacity)| Rider {
+            id,
+            capacity,
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (10..16)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let affinity = HashMap::from([
+        ((10, 1), 5),
+        ((10, 2), 3),
+        // Rider 1 is full after order 10, so the next best takes it
+        ((11, 1), 9),
+        ((11, 3), 2),
+        ((12, 2), -1),
+    ]);
+
+    // 13 to 15 have no preference and balance over whoever has room
+    assert_eq!(
+        compute_plan_affinity(&riders, &orders, &affinity),
+        Plan::from_iter([(1, vec![10]), (2, vec![13, 14, 15]), (3, vec![11, 12])])
+    );
+}
+
+#[test]
+fn reserved_orders_stay_out_until_unreserved() {
+    let riders: Vec<Rider> = [1, 2]
+        .into_iter()
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (10..14)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let mut dispatcher = Dispatcher::new(&riders, &orders);
+
+    dispatcher.reserve(11);
+    assert!(!dispatcher.is_assigned(11));
+    dispatcher.recompute();
+    assert_eq!(
+        dispatcher.plan,
+        Plan::from_iter([(1, vec![10, 13]), (2, vec![12])])
+    );
+    assert_eq!(dispatcher.assign_one(11), None);
+    assert_eq!(
+        dispatcher.apply(Event::OrderAdded { order_id: 11 }),
+        ignored(IgnoreReason::Reserved)
+    );
+
+    dispatcher.unreserve(11);
+    assert_eq!(dispatcher.assign_one(11), Some(2));
+    dispatcher.recompute();
+    assert_eq!(
+        dispatcher.plan,
+        Plan::from_iter([(1, vec![10, 12]), (2, vec![11, 13])])
+    );
+}
+
+#[test]
+fn nearest_distances_add_up_to_plan_cost() {
+    let riders: Vec<Rider> = [(1, (0.0, 0.0)), (2, (10.0, 10.0))]
+        .into_iter()
+        .map(|(id, location)| Rider {
+            id,
+            location: Some(location),
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = [
+        (1, (0.0, 1.0)),
+        (2, (1.0, 0.0)),
+        (3, (10.0, 10.0)),
+        (4, (11.0, 10.0)),
+    ]
+    .into_iter()
+    .map(|(id, location)| Order {
+        id,
+        location: Some(location),
+        ..Default::default()
+    })
+    .collect();
+
+    let (plan, distances) = compute_plan_nearest_with_distances(&riders, &orders);
+    assert_eq!(plan, Plan::from_iter([(1, vec![1, 2]), (2, vec![3, 4])]));
+    let one_degree_km = EARTH_RADIUS_KM * std::f64::consts::PI / 180.0;
+    assert!((distances[&1] - 2.0 * one_degree_km).abs() < 1e-9);
+    assert!((distances[&2] - one_degree_km).abs() < 1e-9);
+
+    let total: f64 = distances.values().sum();
+    assert!((total - plan_cost(&plan, &riders, &orders, 1.0)).abs() < 1e-9);
+}
+
+#[test]
+fn drain_all_hands_back_every_order() {
+    let mut plan = Plan::from_iter([(2, vec![7, 3]), (1, vec![5]), (4, vec![])]);
+    assert!(!is_empty(&plan));
+
+    assert_eq!(drain_all(&mut plan), vec![3, 5, 7]);
+    assert!(is_empty(&plan));
+    assert_eq!(
+        plan,
+        Plan::from_iter([(1, vec![]), (2, vec![]), (4, vec![])])
+    );
+    assert!(drain_all(&mut plan).is_empty());
+}
+
+#[test]
+fn event_log_reads_back_and_flags_bad_lines() {
+    let log = "REJECT rider=3 order=42\nCANCEL order=7\n\nREASSIGN to=1 order=9\n";
+    assert_eq!(
+        parse_event_log(log),
+        Ok(vec![
+            Event::RiderRejected {
+                rider_id: 3,
+                order_id: 42
+            },
+            Event::OrderCanceled { order_id: 7 },
+            Event::OrderReassigned { order_id: 9, to: 1 },
+        ])
+    );
+    assert_eq!(
+        parse_event_log("CANCEL order=7\nLOSE order=8"),
+        Err(ParseError::UnknownEvent { line: 2 })
+    );
+    for bad in [
+        "CANCEL",
+        "CANCEL order=x",
+        "CANCEL order=1 order=2",
+        "CANCEL order=1 rider=2",
+        "REJECT rider=3",
+        "ADD_RIDER rider=4294967296",
+    ] {
+        assert_eq!(parse_event_log(bad), Err(ParseError::BadField { line: 1 }));
     }
+}
 
+#[test]
+fn interned_plan_returns_original_ids_in_less_space() {
+    let ids: Vec<String> = (0..10).map(|i| format!("order-{i:032}")).collect();
+    let mut interned = InternedPlan::default();
+    let mut plain: HashMap<u32, Vec<String>> = HashMap::new();
+    // Every id is queued on each of 20 riders
+    for rider_id in 0..20 {
+        for id in &ids {
+            interned.assign(rider_id, id.clone());
+            plain.entry(rider_id).or_default().push(id.clone());
+        }
+    }
 
+    for rider_id in 0..20 {
+        assert_eq!(interned.orders_of(rider_id), ids.iter().collect::<Vec<_>>());
+    }
+    assert!(interned.orders_of(99).is_empty());
+    assert_eq!(interned.orders.resolve(0), ids.first());
+    assert_eq!(interned.orders.resolve(10), None);
 
+    let queued_bytes = |strings: &mut dyn Iterator<Item = &String>| -> usize {
+        strings
+            .map(|s| std::mem::size_of::<String>() + s.capacity())
+            .sum()
+    };
+    let plain_bytes = queued_bytes(&mut plain.values().flatten());
+    let interned_bytes = interned.plan.values().map(|h| h.len() * 4).sum::<usize>()
+        + queued_bytes(&mut interned.orders.ids.iter())
+        + queued_bytes(&mut interned.orders.handles.keys());
+    assert!(
+        interned_bytes * 4 < plain_bytes,
+        "{interned_bytes} vs {plain_bytes}"
+    );
+}
 
+#[test]
+fn stale_orders_lose_out_under_capacity_pressure() {
+    let riders = [Rider {
+        id: 1,
+        capacity: Some(1),
+        ..Default::default()
+    }];
+    let orders: Vec<Order> = [(1, 3, 0), (2, 2, 100)]
+        .into_iter()
+        .map(|(id, priority, created_at)| Order {
+            id,
+            priority,
+            created_at,
+            ..Default::default()
+        })
+        .collect();
 
+    // Without decay the older, more urgent order wins the only slot
+    let (plan, leftovers) = compute_plan_soft_cap(&riders, &orders, 100, 0.0);
+    assert_eq!(plan, Plan::from_iter([(1, vec![1])]));
+    assert_eq!(leftovers, vec![2]);
 
+    // After 100 ticks at 0.01 order 1 is down to 3 / e, below the fresh order
+    let mut decayed = orders.clone();
+    decay_priorities(&mut decayed, 100, 0.01);
+    assert_eq!(
+        decayed.iter().map(|o| o.priority).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    let (plan, leftovers) = compute_plan_soft_cap(&riders, &orders, 100, 0.01);
+    assert_eq!(plan, Plan::from_iter([(1, vec![2])]));
+    assert_eq!(leftovers, vec![1]);
+}
 
+#[test]
+fn soft_cap_compares_fractional_priorities() {
+    let riders = vec![Rider {
+        id: 1,
+        capacity: Some(1),
+        ..Default::default()
+    }];
+    let order = |id, priority, created_at| Order {
+        id,
+        priority,
+        created_at,
+        ..Default::default()
+    };
 
+    // At 0.01 a priority 2 order aged 10 is still at 1.81, ahead of a fresh 1
+    let orders = vec![order(1, 1, 100), order(2, 2, 90)];
+    let (plan, leftovers) = compute_plan_soft_cap(&riders, &orders, 100, 0.01);
+    assert_eq!(plan, Plan::from_iter([(1, vec![2])]));
+    assert_eq!(leftovers, vec![1]);
 
-    #[test]
-    fn events_over_time(starting_plan: Plan, test_events: Vec<TestEvent>) {
-        prop_assume!(starting_plan.len() > 1);
-        prop_assume!(starting_plan.values().all(|orders| !orders.is_empty()));
-        prop_assume!(starting_plan.values().flatten().all_unique());
+    // 1.21 and 1.41 both round to 1, but the younger order is still ahead
+    let orders = vec![order(1, 2, 50), order(2, 2, 65)];
+    let mut decayed = orders.clone();
+    decay_priorities(&mut decayed, 100, 0.01);
+    assert_eq!(
+        decayed.iter().map(|o| o.priority).collect::<Vec<_>>(),
+        vec![1, 1]
+    );
+    let (plan, leftovers) = compute_plan_soft_cap(&riders, &orders, 100, 0.01);
+    assert_eq!(plan, Plan::from_iter([(1, vec![2])]));
+    assert_eq!(leftovers, vec![1]);
+}
 
-        let events : Vec<Event> = test_events.into_iter().map(|test_event| test_event.into_event(&starting_plan)).collect();
-        let canceled_orders : HashSet<_> = events.iter()
-            .flat_map(|e| if let Event::OrderCanceled{order_id} = e { Some(*order_id) } else { None })
+#[test]
+fn scaling_rounds_up_to_whole_riders() {
+    let plan: Plan = (1..=4).map(|id| (id, vec![])).collect();
+
+    // 40 orders fit four riders of ten exactly
+    assert_eq!(riders_needed(40, 10), Some(4));
+    assert_eq!(recommend_scaling(&plan, 40, 10), Some(0));
+    // One more needs a whole extra rider
+    assert_eq!(riders_needed(41, 10), Some(5));
+    assert_eq!(recommend_scaling(&plan, 41, 10), Some(1));
+    // 11 orders need two riders, so two can go
+    assert_eq!(riders_needed(11, 10), Some(2));
+    assert_eq!(recommend_scaling(&plan, 11, 10), Some(-2));
+    assert_eq!(recommend_scaling(&plan, 0, 10), Some(-4));
+    // Riders who carry nothing can't cover any forecast
+    assert_eq!(riders_needed(40, 0), None);
+    assert_eq!(recommend_scaling(&plan, 0, 0), None);
+}
+
+#[test]
+fn shared_plan_matches_single_threaded_replay() {
+    let events: Vec<Event> = (0..200u64)
+        .map(|n| match n % 4 {
+            0 => Event::OrderAdded { order_id: 100 + n },
+            1 => Event::RiderRejected {
+                rider_id: 1,
+                order_id: 10 + n % 3,
+            },
+            2 => Event::OrderReassigned {
+                order_id: 100 + n - 2,
+                to: 2,
+            },
+            _ => Event::OrderCanceled {
+                order_id: 100 + n - 3,
+            },
+        })
+        .collect();
+    // Cloned so both sides share a hasher; rejections go to the first other
+    // rider in iteration order
+    let start = fuzz_plan();
+    let shared = SharedPlan::new(start.clone());
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..200 {
+                    assert_eq!(validate_plan(&shared.read()), Ok(()));
+                }
+            });
+        }
+        scope.spawn(|| {
+            for event in &events {
+                shared.apply(*event);
+            }
+        });
+    });
+    assert_eq!(
+        *shared.read(),
+        replay(start, &events, &mut MoveClock::default())
+    );
+
+    // A writer dying mid-update poisons the lock but not the plan
+    let before = shared.read().clone();
+    let poisoner = shared.clone();
+    let _ = std::thread::spawn(move || {
+        let _plan = poisoner.write();
+        panic!("writer died");
+    })
+    .join();
+    assert!(shared.0.is_poisoned());
+    assert_eq!(*shared.read(), before);
+    let outcome = shared.apply(Event::OrderAdded { order_id: 1000 });
+    assert!(matches!(
+        outcome,
+        EventOutcome::Assigned { order_id: 1000, .. }
+    ));
+}
+
+#[test]
+fn canceling_a_duplicated_order_clears_every_copy() {
+    let plan = Plan::from_iter([(1, vec![10, 11, 10]), (2, vec![10, 20]), (3, vec![21])]);
+    assert!(validate_plan(&plan).is_err());
+
+    let (plan, outcome) = process_event(plan, Event::OrderCanceled { order_id: 10 });
+    assert_eq!(
+        outcome,
+        EventOutcome::Canceled {
+            order_id: 10,
+            moves: vec![]
+        }
+    );
+    assert_eq!(
+        plan,
+        Plan::from_iter([(1, vec![11]), (2, vec![20]), (3, vec![21])])
+    );
+}
+
+#[test]
+fn congestion_pushes_orders_to_other_riders() {
+    let riders: Vec<Rider> = [(1, 0.0), (2, 1.0)]
+        .into_iter()
+        .map(|(id, lon)| Rider {
+            id,
+            location: Some((0.0, lon)),
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = [(1, 0.2), (2, 0.4), (3, 0.9)]
+        .into_iter()
+        .map(|(id, lon)| Order {
+            id,
+            location: Some((0.0, lon)),
+            ..Default::default()
+        })
+        .collect();
+
+    let (plan, _) = compute_plan_nearest_by(&riders, &orders, |_, _| 1.5);
+    assert_eq!(plan, Plan::from_iter([(1, vec![1, 2]), (2, vec![3])]));
+
+    // Trips out of rider 1's corner are five times slower
+    let jammed = |from: (f64, f64), _| if from.1 < 0.5 { 5.0 } else { 1.0 };
+    let (plan, leftovers) = compute_plan_nearest_by(&riders, &orders, jammed);
+    assert_eq!(plan, Plan::from_iter([(2, vec![1, 2, 3])]));
+    assert!(leftovers.is_empty());
+}
+
+#[test]
+fn replay_expecting_flags_the_first_wrong_outcome() {
+    let start = Plan::from_iter([(1, vec![10, 11]), (2, vec![20])]);
+    let mut script = vec![
+        (
+            Event::OrderReassigned {
+                order_id: 10,
+                to: 2,
+            },
+            EventOutcome::Reassigned {
+                order_id: 10,
+                from: 1,
+                to: 2,
+            },
+        ),
+        (
+            Event::OrderCanceled { order_id: 20 },
+            EventOutcome::Canceled {
+                order_id: 20,
+                moves: vec![],
+            },
+        ),
+        (
+            Event::OrderCanceled { order_id: 99 },
+            ignored(IgnoreReason::UnknownOrder),
+        ),
+    ];
+    assert_eq!(
+        replay_expecting(start.clone(), &script),
+        Ok(Plan::from_iter([(1, vec![11]), (2, vec![10])]))
+    );
+
+    script[1].1 = EventOutcome::Canceled {
+        order_id: 21,
+        moves: vec![],
+    };
+    let mismatch = replay_expecting(start, &script).unwrap_err();
+    assert_eq!(mismatch.index, 1);
+    assert_eq!(
+        mismatch.expected,
+        EventOutcome::Canceled {
+            order_id: 21,
+            moves: vec![]
+        }
+    );
+    assert_eq!(
+        mismatch.actual,
+        EventOutcome::Canceled {
+            order_id: 20,
+            moves: vec![]
+        }
+    );
+}
+
+#[test]
+fn co_located_orders_share_a_rider() {
+    let riders: Vec<Rider> = (1..=2)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    // Two neighbourhoods, mixed up in the input
+    let orders: Vec<Order> = (0..10)
+        .map(|id| Order {
+            id,
+            location: Some(if id % 3 == 0 {
+                (52.23 + id as f64 * 1e-4, 21.01)
+            } else {
+                (50.06, 19.94 + id as f64 * 1e-4)
+            }),
+            ..Default::default()
+        })
+        .collect();
+
+    assert_eq!(
+        cluster_orders(&orders, 2),
+        vec![vec![0, 3, 6, 9], vec![1, 2, 4, 5, 7, 8]]
+    );
+    assert_eq!(
+        compute_plan_clustered(&riders, &orders, 2),
+        Plan::from_iter([(1, vec![0, 3, 6, 9]), (2, vec![1, 2, 4, 5, 7, 8])])
+    );
+    // Round-robin sends both riders to both neighbourhoods
+    assert_eq!(compute_plan(&riders, &orders)[&1], vec![0, 2, 4, 6, 8]);
+}
+
+#[test]
+fn assignment_functions_survive_empty_and_single_inputs() {
+    let one_rider = [Rider {
+        id: 1,
+        capacity: Some(1),
+        location: Some((0.0, 0.0)),
+        ..Default::default()
+    }];
+    let one_order = [Order {
+        id: 10,
+        location: Some((0.0, 0.0)),
+        ..Default::default()
+    }];
+    let no_riders: &[Rider] = &[];
+    let no_orders: &[Order] = &[];
+    let single = Plan::from_iter([(1, vec![10])]);
+
+    for (riders, orders) in [
+        (no_riders, no_orders),
+        (no_riders, &one_order[..]),
+        (&one_rider[..], no_orders),
+        (&one_rider[..], &one_order[..]),
+    ] {
+        // Either the order is planned on the lone rider or nothing is planned
+        let expected = if riders.is_empty() || orders.is_empty() {
+            Plan::default()
+        } else {
+            single.clone()
+        };
+        let unplanned: Vec<u64> = if riders.is_empty() {
+            orders.iter().map(|o| o.id).collect()
+        } else {
+            vec![]
+        };
+
+        assert_eq!(compute_plan(riders, orders), expected);
+        assert_eq!(compute_plan_tiered(riders, orders), expected);
+        assert_eq!(reassign_fresh(riders, orders).len(), riders.len());
+        assert_eq!(
+            compute_plan_waves(riders, orders, 1)
+                .into_iter()
+                .flat_map(|wave| wave.into_values().flatten())
+                .count(),
+            expected.values().flatten().count()
+        );
+        assert_eq!(compute_plan_clustered(riders, orders, 1), expected);
+        for strategy in Strategy::ALL {
+            assert_eq!(
+                compute_plan_with(strategy, riders, orders),
+                (expected.clone(), unplanned.clone()),
+                "{strategy:?}"
+            );
+        }
+        assert_eq!(
+            compute_plan_capped_rr(riders, orders),
+            (expected.clone(), unplanned.clone())
+        );
+        assert_eq!(
+            compute_plan_skill(riders, orders),
+            (expected.clone(), unplanned.clone())
+        );
+        assert_eq!(
+            compute_plan_soft_cap(riders, orders, 0, 0.0),
+            (expected.clone(), unplanned.clone())
+        );
+        assert_eq!(compute_plan_min_riders(riders, orders).0, expected);
+        assert_eq!(
+            compute_plan_affinity(riders, orders, &HashMap::new()),
+            expected
+        );
+        assert_eq!(
+            compute_plan_nearest_with_distances(riders, orders).0,
+            expected
+        );
+        let result = compute_plan_full(riders, orders, &Constraints::ALL);
+        assert_eq!(result.plan, expected);
+        assert_eq!(result.dead_letter.len(), unplanned.len());
+        assert_eq!(
+            is_feasible(riders, orders, &Constraints::ALL).is_ok(),
+            unplanned.is_empty()
+        );
+
+        let mut plan: Plan = riders.iter().map(|r| (r.id, vec![])).collect();
+        let ids: Vec<u64> = orders.iter().map(|o| o.id).collect();
+        assert_eq!(
+            assign_transactional(&mut plan, riders, &ids).is_ok(),
+            unplanned.is_empty()
+        );
+        let mut plan: Plan = riders.iter().map(|r| (r.id, vec![])).collect();
+        add_orders(&mut plan, orders);
+        assert_eq!(
+            plan.values().flatten().count(),
+            expected.values().flatten().count()
+        );
+    }
+    assert_eq!(nth_order(&one_order, 0).map(|o| o.id), Some(10));
+    assert!(nth_order(&one_order, 1).is_none());
+    assert!(nth_order(&[], 0).is_none());
+}
+
+#[test]
+fn rejected_orders_are_shared_out_evenly() {
+    let riders: Vec<Rider> = (1..=4)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (0..40)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let mut dispatcher = Dispatcher::new(&riders, &orders);
+
+    // Rider 1 turns down all its orders, then everyone turns down one each
+    let mut taken: HashMap<u32, usize> = HashMap::new();
+    let mut reject = |dispatcher: &mut Dispatcher, order_id: u64| {
+        let rider_id = rider_of(&dispatcher.plan, order_id).unwrap();
+        match dispatcher.apply(Event::RiderRejected { rider_id, order_id }) {
+            EventOutcome::Reassigned { to, .. } => *taken.entry(to).or_default() += 1,
+            outcome => panic!("{outcome:?}"),
+        }
+    };
+    for order_id in dispatcher.plan[&1].clone() {
+        reject(&mut dispatcher, order_id);
+    }
+    for order_id in (0..40).filter(|id| id % 4 != 0) {
+        reject(&mut dispatcher, order_id);
+    }
+    assert_eq!(taken.values().sum::<usize>(), 40);
+    let (min, max) = (1..=4)
+        .map(|id| taken.get(&id).cloned().unwrap_or(0))
+        .minmax()
+        .into_option()
+        .unwrap();
+    assert!(max - min <= 2, "{taken:?}");
+}
+
+#[test]
+fn stateless_rejections_go_to_the_least_loaded_rider() {
+    let plan = Plan::from_iter([
+        (1, vec![10, 11, 12]),
+        (2, vec![20, 21]),
+        (3, vec![30]),
+        (4, vec![40]),
+    ]);
+    let reject =
+        |plan, rider_id, order_id| process_event(plan, Event::RiderRejected { rider_id, order_id });
+
+    // Riders 3 and 4 are tied on load, the lower id wins
+    let (plan, outcome) = reject(plan, 1, 10);
+    assert_eq!(
+        outcome,
+        EventOutcome::Reassigned {
+            order_id: 10,
+            from: 1,
+            to: 3
+        }
+    );
+    // Now rider 4 alone has the least
+    let (plan, outcome) = reject(plan, 2, 20);
+    assert_eq!(
+        outcome,
+        EventOutcome::Reassigned {
+            order_id: 20,
+            from: 2,
+            to: 4
+        }
+    );
+    assert_eq!(
+        plan,
+        Plan::from_iter([
+            (1, vec![11, 12]),
+            (2, vec![21]),
+            (3, vec![30, 10]),
+            (4, vec![40, 20]),
+        ])
+    );
+}
+
+#[test]
+fn streamed_orders_plan_like_collected_ones() {
+    let csv = "10,1,0,2,\n11,5,1,0,300\n\n12,2,0,3,120\n13,1,2,1,\n14,4,0,0,\n";
+    let riders: Vec<Rider> = (1..=3)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+
+    let orders: Vec<Order> = stream_orders(csv.as_bytes())
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        orders.iter().map(|o| o.id).collect::<Vec<_>>(),
+        vec![10, 11, 12, 13, 14]
+    );
+    assert_eq!(
+        (orders[1].weight, orders[1].zone, orders[1].deadline),
+        (5, 1, Some(300))
+    );
+    assert_eq!(orders[0].deadline, None);
+
+    let streamed =
+        compute_plan_streaming(&riders, stream_orders(csv.as_bytes()).map(Result::unwrap));
+    assert_eq!(streamed, compute_plan(&riders, &orders));
+
+    let errors: Vec<CsvError> =
+        stream_orders("10,1,0,2,\n11,1,0\nx,1,0,0,\n13,1,0,0,soon\n14,1,0,300,\n".as_bytes())
+            .filter_map(Result::err)
             .collect();
-        println!("total starting orders {}, events {}", starting_plan.values().map(|v| v.len()).sum::<usize>(), canceled_orders.len());
-        let mut current_plan = starting_plan.clone();
-        for event in events {
-            let orders_before : HashSet<_> = current_plan.values().flatten().cloned().collect();
-            current_plan = process_event(current_plan, event.clone());
-            if let Event::RiderRejected{rider_id,order_id} = event {
-                let orders_after : HashSet<_> = current_plan.values().flatten().cloned().collect();
-                assert_eq!(orders_before, orders_after);
-                assert!(!current_plan[&rider_id].contains(&order_id));
+    assert_eq!(
+        errors,
+        vec![
+            CsvError::Columns { line: 2 },
+            CsvError::BadField {
+                line: 3,
+                column: "id"
+            },
+            CsvError::BadField {
+                line: 4,
+                column: "deadline"
+            },
+            CsvError::BadField {
+                line: 5,
+                column: "priority"
+            },
+        ]
+    );
+}
+
+#[test]
+fn plan_from_assignments_checks_the_roster() {
+    let riders: Vec<Rider> = (1..=3)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+
+    assert_eq!(
+        plan_from_assignments(&riders, &[(1, 10), (2, 20), (1, 11)]),
+        Ok(Plan::from_iter([(1, vec![10, 11]), (2, vec![20])]))
+    );
+    assert_eq!(plan_from_assignments(&riders, &[]), Ok(Plan::default()));
+    assert_eq!(
+        plan_from_assignments(&riders, &[(1, 10), (4, 11), (1, 10)]),
+        Err(PlanError::UnknownRider { id: 4 })
+    );
+    assert_eq!(
+        plan_from_assignments(&riders, &[(1, 10), (2, 11), (3, 10)]),
+        Err(PlanError::DuplicateOrder { order_id: 10 })
+    );
+    assert_eq!(
+        plan_from_assignments(&riders, &[(2, 10), (2, 10)]),
+        Err(PlanError::DuplicateOrder { order_id: 10 })
+    );
+}
+
+#[test]
+fn optimized_queue_travels_no_further() {
+    let rider = Rider {
+        id: 1,
+        location: Some((0.0, 0.0)),
+        ..Default::default()
+    };
+    let coords: HashMap<u64, (f64, f64)> = [
+        (10, (0.0, 0.3)),
+        (11, (0.0, 0.1)),
+        (12, (0.1, 0.3)),
+        (13, (0.0, 0.2)),
+        (14, (0.2, 0.0)),
+    ]
+    .into_iter()
+    .collect();
+    let travel = |queue: &[u64]| {
+        let mut at = rider.location.unwrap();
+        let mut total = 0.0;
+        for order_id in queue {
+            total += haversine_km(at, coords[order_id]);
+            at = coords[order_id];
+        }
+        total
+    };
+
+    for original in [
+        vec![10, 11, 12, 13, 14],
+        vec![14, 12, 10, 13, 11],
+        vec![12, 14],
+    ] {
+        let mut queue = original.clone();
+        optimize_queue(&rider, &coords, &mut queue);
+        assert!(
+            travel(&queue) <= travel(&original),
+            "{original:?} -> {queue:?}"
+        );
+        assert_eq!(
+            queue.iter().sorted().collect::<Vec<_>>(),
+            original.iter().sorted().collect::<Vec<_>>()
+        );
+    }
+    let mut queue = vec![10, 99, 11, 12, 13, 14];
+    optimize_queue(&rider, &coords, &mut queue);
+    assert_eq!(queue, vec![11, 13, 10, 12, 14, 99]);
+}
+
+#[test]
+fn conservation_check_catches_a_dropped_order() {
+    let reject = Event::RiderRejected {
+        rider_id: 1,
+        order_id: 10,
+    };
+    let (plan, outcome) = process_event_checked(fuzz_plan(), reject).unwrap();
+    assert!(matches!(outcome, EventOutcome::Reassigned { .. }));
+    let cancel = Event::OrderCanceled { order_id: 11 };
+    assert!(process_event_checked(plan, cancel).is_ok());
+
+    // A handler that forgets to put the rejected order back
+    let buggy = |mut plan: Plan, event| {
+        if let Event::RiderRejected { rider_id, order_id } = event {
+            plan.get_mut(&rider_id).unwrap().retain(|v| *v != order_id);
+        }
+        (plan, ignored(IgnoreReason::NoOtherRider))
+    };
+    assert_eq!(
+        check_conservation(fuzz_plan(), reject, buggy),
+        Err(ConservationError {
+            event: reject,
+            lost: vec![10],
+            gained: vec![],
+        })
+    );
+    // Dropping orders is what a cancel is for
+    assert!(check_conservation(fuzz_plan(), cancel, |plan, _| (
+        Plan::default(),
+        ignored(IgnoreReason::UnknownOrder)
+    ))
+    .is_ok());
+}
+
+#[test]
+fn round_robin_plans_score_as_fair() {
+    let riders: Vec<Rider> = (1..=10)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (0..95)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+
+    // Five riders with 10 orders and five with 9
+    let report = fairness_report(&compute_plan(&riders, &orders));
+    assert!(report.gini < 0.05, "{report:?}");
+    assert!(report.coefficient_of_variation < 0.1, "{report:?}");
+    assert_eq!(report.max_min_ratio, 10.0 / 9.0);
+
+    // One rider holding everything is as unfair as ten riders get
+    let mut lopsided: Plan = (1..=10).map(|id| (id, vec![])).collect();
+    lopsided.insert(1, (0..95).collect());
+    let report = fairness_report(&lopsided);
+    assert!((report.gini - 0.9).abs() < 1e-9, "{report:?}");
+    assert_eq!(report.max_min_ratio, f64::INFINITY);
+
+    let balanced = fairness_report(&Plan::from_iter([(1, vec![10]), (2, vec![20])]));
+    assert_eq!(
+        (
+            balanced.gini,
+            balanced.coefficient_of_variation,
+            balanced.max_min_ratio
+        ),
+        (0.0, 0.0, 1.0)
+    );
+    assert_eq!(fairness_report(&Plan::default()), balanced);
+}
+
+#[test]
+fn repeat_addresses_go_back_to_their_rider() {
+    let riders: Vec<Rider> = (1..=3)
+        .map(|id| Rider {
+            id,
+            capacity: Some(2),
+            ..Default::default()
+        })
+        .collect();
+    let order = |id, address_id| Order {
+        id,
+        address_id: Some(address_id),
+        ..Default::default()
+    };
+    let mut history = History::default();
+    history.riders_by_address.insert(500, 3);
+
+    // Address 500 goes to rider 3 until they are full
+    let monday = [order(1, 500), order(2, 600), order(3, 500), order(4, 500)];
+    let plan = compute_plan_with_history(&riders, &monday, &history);
+    assert_eq!(
+        plan,
+        Plan::from_iter([(1, vec![2]), (2, vec![4]), (3, vec![1, 3])])
+    );
+
+    // Recording Monday makes rider 1 the regular for address 600
+    history.record(&plan, &monday);
+    assert_eq!(history.riders_by_address[&600], 1);
+    let tuesday = [order(5, 700), order(6, 600)];
+    assert_eq!(
+        compute_plan_with_history(&riders, &tuesday, &history),
+        Plan::from_iter([(1, vec![5, 6])])
+    );
+    assert_eq!(
+        compute_plan_with_history(&riders, &tuesday, &History::default()),
+        Plan::from_iter([(1, vec![5]), (2, vec![6])])
+    );
+}
+
+#[test]
+fn large_test_event_indices_wrap_without_truncation() {
+    // Orders 10, 11, 12 and 20 once sorted
+    let plan = fuzz_plan();
+    for which_order in [
+        usize::MAX,
+        usize::MAX - 1,
+        usize::MAX / 2,
+        usize::MAX / 2 + 1,
+    ] {
+        let expected = [10, 11, 12, 20][which_order % 4];
+        assert_eq!(
+            TestEvent::OrderCanceled { which_order }.into_event(&plan),
+            Event::OrderCanceled { order_id: expected }
+        );
+        assert_eq!(
+            TestEvent::OrderHeld { which_order }.into_event(&plan),
+            Event::OrderHeld { order_id: expected }
+        );
+    }
+    // usize::MAX % 3 == 0 picks rider 1's first order
+    assert_eq!(
+        TestEvent::RiderRejected {
+            which_rider: usize::MAX,
+            which_order: usize::MAX,
+        }
+        .into_event(&plan),
+        Event::RiderRejected {
+            rider_id: 1,
+            order_id: 10
+        }
+    );
+}
+
+#[test]
+fn least_loaded_only_beats_round_robin_on_loaded_plans() {
+    let riders: Vec<Rider> = (1..=4)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (100..110)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+
+    // From an empty plan both end up with the same spread
+    let round_robin = compute_plan_with(Strategy::RoundRobin, &riders, &orders).0;
+    let least_loaded = compute_plan_with(Strategy::LeastLoaded, &riders, &orders).0;
+    assert_eq!(
+        assignment_quality(&round_robin),
+        assignment_quality(&least_loaded)
+    );
+
+    // Rider 1 is already busy: round-robin still deals it a full share, while
+    // least-loaded fills the others up first
+    let start = Plan::from_iter([(1, vec![1, 2, 3, 4]), (2, vec![]), (3, vec![]), (4, vec![])]);
+    let mut round_robin = start.clone();
+    for (rider_id, new) in compute_plan(&riders, &orders) {
+        round_robin.get_mut(&rider_id).unwrap().extend(new);
+    }
+    let mut least_loaded = start;
+    add_orders(&mut least_loaded, &orders);
+    // Loads 5, 5, 4, 4
+    assert_eq!(assignment_quality(&least_loaded), 0.5);
+    assert!(assignment_quality(&least_loaded) < assignment_quality(&round_robin));
+}
+
+#[test]
+fn riders_beyond_the_orders_are_reported_idle() {
+    let riders: Vec<Rider> = (1..=5)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let orders: Vec<Order> = (10..13)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let (plan, idle) = compute_plan_report_idle(&riders, &orders);
+    assert_eq!(idle, vec![4, 5]);
+    assert!(idle.iter().all(|id| !plan.contains_key(id)));
+    assert_eq!(
+        plan.values()
+            .flatten()
+            .cloned()
+            .sorted()
+            .collect::<Vec<_>>(),
+        vec![10, 11, 12]
+    );
+
+    let (plan, idle) = compute_plan_report_idle(&riders, &orders[..0]);
+    assert!(plan.is_empty());
+    assert_eq!(idle, vec![1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn canceled_orders_stay_canceled_when_reassigned() {
+    let start = fuzz_plan();
+    let events = [
+        Event::OrderCanceled { order_id: 10 },
+        Event::OrderReassigned {
+            order_id: 10,
+            to: 2,
+        },
+        Event::RiderRejected {
+            rider_id: 1,
+            order_id: 10,
+        },
+        Event::OrderCanceled { order_id: 20 },
+        Event::OrderReassigned {
+            order_id: 20,
+            to: 3,
+        },
+    ];
+    let starting: HashSet<u64> = start.values().flatten().cloned().collect();
+    let canceled = HashSet::from([10, 20]);
+
+    let plan = replay(start.clone(), &events, &mut MoveClock::default());
+    assert_partition(
+        &starting,
+        &canceled,
+        &plan.values().flatten().cloned().collect(),
+    );
+
+    let mut dispatcher = Dispatcher::from_plan(start);
+    for event in events {
+        dispatcher.apply(event);
+    }
+    assert_partition(
+        &starting,
+        &canceled,
+        &dispatcher.plan.values().flatten().cloned().collect(),
+    );
+}
+
+fn check_store_round_trip(store: &dyn PlanStore) {
+    assert_eq!(store.load("missing"), Ok(None));
+    let plan = Plan::from_iter([(1, vec![10, 11]), (2, vec![]), (3, vec![30])]);
+    store.save("today", &plan).unwrap();
+    assert_eq!(store.load("today"), Ok(Some(plan)));
+    store.save("today", &Plan::default()).unwrap();
+    assert_eq!(store.load("today"), Ok(Some(Plan::default())));
+    assert_eq!(store.load("missing"), Ok(None));
+}
+
+#[test]
+fn plan_stores_round_trip() {
+    check_store_round_trip(&MemStore::default());
+
+    let dir = std::env::temp_dir().join(format!("prop-store-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("broken.json"), "[1,2]").unwrap();
+    let store = FileStore { dir: dir.clone() };
+    check_store_round_trip(&store);
+    assert_eq!(
+        store.load("broken"),
+        Err(StoreError::Corrupt {
+            key: "broken".to_string()
+        })
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn file_store_refuses_keys_that_leave_its_dir() {
+    let dir = std::env::temp_dir().join(format!("prop-store-keys-{}", std::process::id()));
+    let store = FileStore {
+        dir: dir.join("plans"),
+    };
+    std::fs::create_dir_all(&store.dir).unwrap();
+
+    for key in ["", "../escaped", "nested/plan", "nested\\plan", ".."] {
+        let invalid = Err(StoreError::InvalidKey {
+            key: key.to_string(),
+        });
+        assert_eq!(store.save(key, &Plan::default()), invalid);
+        assert_eq!(store.load(key), invalid.map(|()| None));
+    }
+    assert!(!dir.join("escaped.json").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn overflow_rider_mops_up_what_does_not_fit() {
+    let riders: Vec<Rider> = [1, 2, 3]
+        .map(|id| Rider {
+            id,
+            capacity: Some(2),
+            ..Default::default()
+        })
+        .to_vec();
+    let orders: Vec<Order> = (10..18)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+
+    let (capped, leftovers) = compute_plan_capped_rr(&riders, &orders);
+    assert_eq!(leftovers, vec![16, 17]);
+    let plan = compute_plan_with_overflow(&riders, &orders, 99);
+    assert_eq!(plan[&99], leftovers);
+    plan.iter()
+        .filter(|(rider_id, _)| **rider_id != 99)
+        .for_each(|(rider_id, queue)| assert_eq!(queue, &capped[rider_id]));
+
+    // With room for everything the overflow rider is just another rider
+    let plan = compute_plan_with_overflow(&riders, &orders[..5], 99);
+    assert!(!plan.contains_key(&99));
+    let plan = compute_plan_with_overflow(&riders, &orders[..5], 3);
+    assert_eq!(plan[&3], vec![12]);
+}
+
+#[test]
+fn diff_plans_lists_moved_orders() {
+    let before = Plan::from_iter([(1, vec![10, 11]), (2, vec![20]), (3, vec![])]);
+    let after = Plan::from_iter([(1, vec![10]), (2, vec![]), (3, vec![20, 11, 30])]);
+    assert_eq!(
+        diff_plans(&before, &after),
+        vec![
+            PlanChange::Moved {
+                order_id: 11,
+                from: 1,
+                to: 3
+            },
+            PlanChange::Moved {
+                order_id: 20,
+                from: 2,
+                to: 3
+            },
+        ]
+    );
+    let mut replayed = before.clone();
+    apply_changes(&mut replayed, &diff_plans(&before, &after));
+    assert!(plans_equivalent(
+        &replayed,
+        &Plan::from_iter([(1, vec![10]), (3, vec![20, 11])])
+    ));
+}
+
+#[test]
+fn canceling_a_prerequisite_cascades_to_dependents() {
+    let order = |id, after| Order {
+        id,
+        after,
+        ..Default::default()
+    };
+    // 1 <- 2 <- 3 and 10 <- {11, 12} <- 13, with 20 on its own
+    let orders = [
+        order(1, None),
+        order(2, Some(1)),
+        order(3, Some(2)),
+        order(10, None),
+        order(11, Some(10)),
+        order(12, Some(10)),
+        order(13, Some(11)),
+        order(13, Some(12)),
+        order(20, None),
+    ];
+    let mut plan = Plan::from_iter([(1, vec![1, 10, 20]), (2, vec![2, 11, 13]), (3, vec![3, 12])]);
+
+    assert_eq!(cancel_cascade(&mut plan, &orders, 2), vec![2, 3]);
+    assert_eq!(cancel_cascade(&mut plan, &orders, 10), vec![10, 11, 12, 13]);
+    assert!(plans_equivalent(
+        &plan,
+        &Plan::from_iter([(1, vec![1, 20])])
+    ));
+    assert_eq!(cancel_cascade(&mut plan, &orders, 1), vec![1, 2, 3]);
+}
+
+#[test]
+fn moving_one_order_changes_at_most_two_snapshot_lines() {
+    let before = Plan::from_iter([(1, vec![12, 10]), (2, vec![20]), (3, vec![]), (4, vec![40])]);
+    assert_eq!(
+        plan_to_sorted_lines(&before),
+        vec!["1=10,12", "2=20", "3=", "4=40"]
+    );
+    let mut after = before.clone();
+    apply_changes(
+        &mut after,
+        &[PlanChange::Moved {
+            order_id: 12,
+            from: 1,
+            to: 3,
+        }],
+    );
+    let changed = plan_to_sorted_lines(&before)
+        .into_iter()
+        .zip(plan_to_sorted_lines(&after))
+        .filter(|(a, b)| a != b)
+        .count();
+    assert_eq!(changed, 2);
+
+    assert_eq!(
+        plan_from_sorted_lines(&["1=10", "x=20"]),
+        Err(ParseError::BadField { line: 2 })
+    );
+    assert_eq!(
+        plan_from_sorted_lines(&["1=10", "1=11"]),
+        Err(ParseError::BadField { line: 2 })
+    );
+    assert_eq!(
+        plan_from_sorted_lines(&["1=10,,11"]),
+        Err(ParseError::BadField { line: 1 })
+    );
+}
+
+#[test]
+fn briefly_unavailable_rider_still_gets_the_order() {
+    let plan = Plan::from_iter([(1, vec![]), (2, vec![20])]);
+    let policy = RetryPolicy { max_attempts: 3 };
+    // Rider 1 answers from the `up_after`th check on, rider 2 always does
+    let flaky = |up_after: usize| {
+        let checks = std::cell::Cell::new(0);
+        move |rider_id: u32| {
+            if rider_id != 1 {
+                return true;
             }
+            checks.set(checks.get() + 1);
+            checks.get() >= up_after
         }
-        let remaining_orders : HashSet<u64> = current_plan.values().flatten().cloned().collect();
-        assert!(canceled_orders.iter().all(|canceled| !remaining_orders.contains(canceled)));
-        assert_eq!(starting_plan.values().flatten().collect::<HashSet<_>>(), 
-            canceled_orders.iter().chain(remaining_orders.iter()).collect());
+    };
+
+    let mut waited = plan.clone();
+    assert_eq!(
+        assign_one_with_retry(&mut waited, 10, policy, flaky(3)),
+        Some(1)
+    );
+    assert_eq!(waited[&1], vec![10]);
+
+    let mut gave_up = plan.clone();
+    assert_eq!(
+        assign_one_with_retry(&mut gave_up, 10, policy, flaky(4)),
+        Some(2)
+    );
+    assert_eq!(gave_up[&2], vec![20, 10]);
+
+    let mut nobody = plan;
+    assert_eq!(
+        assign_one_with_retry(&mut nobody, 10, policy, |_| false),
+        None
+    );
+    assert_eq!(nobody.values().flatten().count(), 1);
+}
+
+#[test]
+fn uneven_plans_break_the_sla() {
+    let rider = |id, capacity| Rider {
+        id,
+        capacity,
+        ..Default::default()
+    };
+    let orders: Vec<Order> = (10..17)
+        .map(|id| Order {
+            id,
+            ..Default::default()
+        })
+        .collect();
+
+    let balanced = [rider(1, None), rider(2, None), rider(3, None)];
+    let plan = compute_plan_sla(&balanced, &orders, 1).unwrap();
+    assert_eq!(plan.values().flatten().count(), 7);
+
+    // Two riders can only take one order each, the third gets the other five
+    let constrained = [rider(1, Some(1)), rider(2, Some(1)), rider(3, None)];
+    assert_eq!(
+        compute_plan_sla(&constrained, &orders, 1),
+        Err(SlaViolation { actual_spread: 4 })
+    );
+    assert!(compute_plan_sla(&constrained, &orders, 4).is_ok());
+    // An idle rider counts towards the spread
+    assert_eq!(
+        compute_plan_sla(&balanced, &orders[..1], 0),
+        Err(SlaViolation { actual_spread: 1 })
+    );
+}
+
+#[test]
+fn reconcile_trusts_rider_reports() {
+    let mut plan = Plan::from_iter([(1, vec![10, 11]), (2, vec![20, 21]), (3, vec![30])]);
+    // Rider 1 never got 11 and picked up 21 instead; rider 3 didn't report
+    let reported = HashMap::from([(1, vec![10, 21]), (2, vec![20]), (4, vec![40])]);
+
+    assert_eq!(
+        reconcile(&mut plan, &reported),
+        vec![
+            Discrepancy::Missing {
+                rider_id: 1,
+                order_id: 11
+            },
+            Discrepancy::Unexpected {
+                rider_id: 1,
+                order_id: 21
+            },
+            Discrepancy::Missing {
+                rider_id: 2,
+                order_id: 21
+            },
+            Discrepancy::Unexpected {
+                rider_id: 4,
+                order_id: 40
+            },
+        ]
+    );
+    assert_eq!(
+        plan,
+        Plan::from_iter([
+            (1, vec![10, 21]),
+            (2, vec![20]),
+            (3, vec![30]),
+            (4, vec![40])
+        ])
+    );
+    assert_eq!(reconcile(&mut plan, &reported), vec![]);
+}
+
+#[test]
+fn recompute_zone_leaves_other_zones_alone() {
+    let riders: Vec<Rider> = [(1, 1), (2, 1), (3, 2), (4, 2)]
+        .map(|(id, zone)| Rider {
+            id,
+            zone,
+            ..Default::default()
+        })
+        .to_vec();
+    let order = |id, zone| Order {
+        id,
+        zone,
+        ..Default::default()
+    };
+    let mut plan = Plan::from_iter([
+        (1, vec![11, 13]),
+        (2, vec![12]),
+        (3, vec![21, 22, 23]),
+        (4, vec![]),
+    ]);
+    let zone_one = [(1, plan[&1].clone()), (2, plan[&2].clone())];
+
+    // Zone 2 loses 21 and gains 24 and 25
+    let orders = [
+        order(11, 1),
+        order(12, 1),
+        order(13, 1),
+        order(22, 2),
+        order(23, 2),
+        order(24, 2),
+        order(25, 2),
+    ];
+    recompute_zone(&mut plan, &riders, &orders, 2);
+    for (rider_id, queue) in zone_one {
+        assert_eq!(plan[&rider_id], queue);
+    }
+    assert_eq!(plan[&3], vec![22, 24]);
+    assert_eq!(plan[&4], vec![23, 25]);
+}
+
+#[test]
+fn best_rider_to_add_goes_where_the_orders_are() {
+    let located = |id, location| Rider {
+        id,
+        location: Some(location),
+        ..Default::default()
+    };
+    // Everyone works from the origin while the orders cluster a degree north
+    let riders = [located(1, (0.0, 0.0))];
+    let orders: Vec<Order> = [(10, (1.0, 0.0)), (11, (1.0, 0.1)), (12, (0.9, 0.0))]
+        .map(|(id, location)| Order {
+            id,
+            location: Some(location),
+            ..Default::default()
+        })
+        .to_vec();
+    let (plan, _) = compute_plan_nearest(&riders, &orders);
+    let candidates = [
+        located(7, (-1.0, 0.0)),
+        located(8, (1.0, 0.05)),
+        located(9, (0.5, 0.0)),
+    ];
+
+    assert_eq!(
+        best_rider_to_add(&plan, &riders, &orders, &candidates),
+        Some(8)
+    );
+    assert_eq!(
+        best_rider_to_add(&plan, &riders, &orders, &candidates[..1]),
+        None
+    );
+    assert_eq!(best_rider_to_add(&plan, &riders, &orders, &[]), None);
+}
+
+#[test]
+fn cancel_can_rebalance_the_plan() {
+    // Rider 2 is already one short, losing 20 leaves them two behind
+    let plan = Plan::from_iter([(1, vec![10, 11, 12]), (2, vec![20, 21])]);
+    let cancel = Event::OrderCanceled { order_id: 20 };
+
+    let (plain, outcome) = process_event_with(plan.clone(), cancel, EventOptions::default());
+    assert_eq!(
+        outcome,
+        EventOutcome::Canceled {
+            order_id: 20,
+            moves: vec![]
+        }
+    );
+    assert_eq!(
+        plain,
+        Plan::from_iter([(1, vec![10, 11, 12]), (2, vec![21])])
+    );
+    assert_eq!(load_spread(&plain), 2);
+
+    let options = EventOptions {
+        rebalance_on_cancel: true,
+    };
+    let (rebalanced, outcome) = process_event_with(plan, cancel, options);
+    let moved = PlanChange::Moved {
+        order_id: 12,
+        from: 1,
+        to: 2,
+    };
+    assert_eq!(
+        outcome,
+        EventOutcome::Canceled {
+            order_id: 20,
+            moves: vec![moved]
+        }
+    );
+    assert_eq!(
+        rebalanced,
+        Plan::from_iter([(1, vec![10, 11]), (2, vec![21, 12])])
+    );
+    assert!(load_spread(&rebalanced) <= 1);
+}
+
+#[test]
+fn trace_follows_an_order_until_it_is_canceled() {
+    let start = Plan::from_iter([(1, vec![42, 10]), (2, vec![20]), (3, vec![])]);
+    let events = [
+        Event::OrderReassigned {
+            order_id: 42,
+            to: 2,
+        },
+        Event::OrderCanceled { order_id: 10 },
+        Event::OrderReassigned {
+            order_id: 42,
+            to: 3,
+        },
+        Event::OrderReassigned {
+            order_id: 42,
+            to: 3,
+        },
+        Event::OrderCanceled { order_id: 42 },
+        Event::OrderCanceled { order_id: 20 },
+    ];
+    assert_eq!(
+        trace_order(&start, &events, 42),
+        vec![
+            OrderState::WithRider(1),
+            OrderState::WithRider(2),
+            OrderState::WithRider(3),
+            OrderState::Canceled,
+        ]
+    );
+    assert_eq!(
+        trace_order(&start, &events, 99),
+        vec![OrderState::Unplanned]
+    );
+
+    // The replay keeps no held set, so the release is ignored and the
+    // order stays held
+    let held = [
+        Event::OrderHeld { order_id: 20 },
+        Event::OrderReleased { order_id: 20 },
+    ];
+    assert_eq!(
+        trace_order(&start, &held, 20),
+        vec![OrderState::WithRider(2), OrderState::Held]
+    );
+}
+
+#[test]
+fn advance_clock_cancels_exactly_the_expired_orders() {
+    let orders: Vec<Order> = [
+        (1, Some(10)),
+        (2, Some(20)),
+        (3, None),
+        (4, Some(30)),
+        (5, Some(15)),
+    ]
+    .into_iter()
+    .map(|(id, deadline)| Order {
+        id,
+        deadline,
+        ..Default::default()
+    })
+    .collect();
+    let mut plan = Plan::from_iter([(1, vec![1, 2, 3]), (2, vec![4, 5])]);
+
+    assert!(advance_clock(&mut plan, &orders, 10).is_empty());
+    assert_eq!(advance_clock(&mut plan, &orders, 21), vec![1, 2, 5]);
+    assert_eq!(plan, Plan::from_iter([(1, vec![3]), (2, vec![4])]));
+    assert_eq!(validate_plan(&plan), Ok(()));
+
+    // Already canceled orders are not reported again
+    assert_eq!(advance_clock(&mut plan, &orders, 100), vec![4]);
+    assert_eq!(plan, Plan::from_iter([(1, vec![3]), (2, vec![])]));
+}
+
+#[test]
+fn rejection_storm_unbalances_the_plan() {
+    let start = Plan::from_iter([(1, vec![1, 2, 3]), (2, vec![4, 5, 6])]);
+    let events: Vec<Event> = [1, 2, 3]
+        .into_iter()
+        .map(|order_id| Event::RiderRejected {
+            rider_id: 1,
+            order_id,
+        })
+        .collect();
+
+    // With two riders every rejection lands on the other one, however loaded
+    let snapshots = replay_with_snapshots(start.clone(), &events);
+    assert_eq!(snapshots.len(), events.len() + 1);
+    assert_eq!(snapshots[0], start);
+    assert_eq!(
+        snapshots[3],
+        replay(start, &events, &mut MoveClock::default())
+    );
+    assert_eq!(max_observed_spread(&snapshots), 6);
+}
+
+#[test]
+fn held_orders_leave_the_plan_until_released() {
+    let mut dispatcher = Dispatcher::from_plan(Plan::from_iter([(1, vec![10, 11]), (2, vec![20])]));
+
+    dispatcher.apply(Event::OrderHeld { order_id: 10 });
+    assert_eq!(
+        dispatcher.plan,
+        Plan::from_iter([(1, vec![11]), (2, vec![20])])
+    );
+    assert_eq!(dispatcher.held, HashSet::from([10]));
+
+    // Releasing an order that isn't held, or holding one that isn't planned, does nothing
+    dispatcher.apply(Event::OrderReleased { order_id: 11 });
+    dispatcher.apply(Event::OrderHeld { order_id: 99 });
+    assert_eq!(
+        dispatcher.plan,
+        Plan::from_iter([(1, vec![11]), (2, vec![20])])
+    );
+    assert_eq!(dispatcher.held, HashSet::from([10]));
+
+    dispatcher.apply(Event::OrderReleased { order_id: 10 });
+    assert_eq!(
+        dispatcher.plan,
+        Plan::from_iter([(1, vec![11, 10]), (2, vec![20])])
+    );
+    assert!(dispatcher.held.is_empty());
+}
+
+#[test]
+fn canceling_a_held_order_forgets_it() {
+    let mut dispatcher = Dispatcher::from_plan(Plan::from_iter([(1, vec![10]), (2, vec![20])]));
+    dispatcher.apply(Event::OrderHeld { order_id: 10 });
+    dispatcher.apply(Event::OrderCanceled { order_id: 10 });
+    dispatcher.apply(Event::OrderReleased { order_id: 10 });
+    assert!(dispatcher.held.is_empty());
+    assert_eq!(
+        dispatcher.plan,
+        Plan::from_iter([(1, vec![]), (2, vec![20])])
+    );
+}
+
+#[test]
+fn tie_break_policy_decides_between_equally_loaded_riders() {
+    let picks = |tie_break: TieBreak| {
+        let mut dispatcher = Dispatcher {
+            tie_break,
+            ..Dispatcher::from_plan(Plan::from_iter([(1, vec![]), (2, vec![]), (3, vec![])]))
+        };
+        // Canceling right away keeps all three riders tied for every new order
+        (10..15)
+            .map(|order_id| {
+                let rider_id = dispatcher.add_order(Order {
+                    id: order_id,
+                    ..Default::default()
+                });
+                dispatcher.apply(Event::OrderCanceled { order_id });
+                rider_id.unwrap()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(picks(TieBreak::LowestId), vec![1, 1, 1, 1, 1]);
+    assert_eq!(picks(TieBreak::HighestId), vec![3, 3, 3, 3, 3]);
+    assert_eq!(picks(TieBreak::RoundRobin), vec![1, 2, 3, 1, 2]);
+    assert_eq!(picks(TieBreak::Random(7)), picks(TieBreak::Random(7)));
+    assert!(picks(TieBreak::Random(7))
+        .iter()
+        .all(|id| (1..=3).contains(id)));
+}
+
+#[test]
+fn validate_delta_spots_duplicate_created_by_a_move() {
+    // Rider 2 already (wrongly) had order 10 when it was moved over
+    let (plan, outcome) = process_event(
+        Plan::from_iter([(1, vec![10]), (2, vec![10, 20])]),
+        Event::OrderReassigned {
+            order_id: 10,
+            to: 2,
+        },
+    );
+    assert_eq!(
+        outcome,
+        EventOutcome::Reassigned {
+            order_id: 10,
+            from: 1,
+            to: 2
+        }
+    );
+    let violation = PlanViolation::DuplicateOrder {
+        order_id: 10,
+        riders: vec![2, 2],
+    };
+    assert_eq!(validate_delta(&plan, &outcome), Err(violation.clone()));
+    assert_eq!(validate_plan(&plan), Err(violation));
+}
+
+// Hashing cost of building a 1M-order plan with each hasher, run with
+// `cargo test --release -- --ignored --nocapture plan_map_hashers`
+#[test]
+#[ignore]
+fn plan_map_hashers_on_a_million_orders() {
+    fn fill<S: std::hash::BuildHasher + Default>(
+    ) -> (HashMap<u32, Vec<u64>, S>, std::time::Duration) {
+        let start = std::time::Instant::now();
+        let mut plan: HashMap<u32, Vec<u64>, S> = HashMap::default();
+        for order_id in 0..1_000_000u64 {
+            plan.entry((order_id % 10_000) as u32 * 7919)
+                .or_default()
+                .push(order_id);
+        }
+        (plan, start.elapsed())
+    }
+
+    let (sip, sip_time) = fill::<std::collections::hash_map::RandomState>();
+    let (fx, fx_time) = fill::<FxBuildHasher>();
+    println!("SipHash {sip_time:?}, FxHash {fx_time:?}");
+    assert_eq!(sip.len(), fx.len());
+}
+
+// Per-order time at 1M orders must stay within 3x of the time at 10k, which a
+// quadratic pass would blow through. Best of three runs per size to ride out
+// noise. `cargo test --release -- --ignored --nocapture compute_plan_scales`
+#[test]
+#[ignore]
+fn compute_plan_scales_linearly_with_orders() {
+    let riders: Vec<Rider> = (0..100)
+        .map(|id| Rider {
+            id,
+            ..Default::default()
+        })
+        .collect();
+    let per_order: Vec<f64> = [10_000u64, 100_000, 1_000_000]
+        .into_iter()
+        .map(|count| {
+            let orders: Vec<Order> = (0..count)
+                .map(|id| Order {
+                    id,
+                    ..Default::default()
+                })
+                .collect();
+            let best = (0..3)
+                .map(|_| {
+                    let start = std::time::Instant::now();
+                    let plan = compute_plan(&riders, &orders);
+                    let elapsed = start.elapsed();
+                    assert_eq!(plan.values().map(Vec::len).sum::<usize>(), orders.len());
+                    elapsed
+                })
+                .min()
+                .unwrap();
+            let nanos = best.as_nanos() as f64 / count as f64;
+            println!("{count} orders: {best:?}, {nanos:.1}ns per order");
+            nanos
+        })
+        .collect();
+    assert!(
+        per_order[2] <= per_order[0] * 3.0,
+        "per-order time grew from {:.1}ns to {:.1}ns",
+        per_order[0],
+        per_order[2]
+    );
+}
+
+// Speed and balance of round-robin against least-loaded over random inputs,
+// from an empty plan and from one where a quarter of the riders start busy.
+// `cargo test --release -- --ignored --nocapture round_robin_vs_least_loaded`
+#[test]
+#[ignore]
+fn round_robin_vs_least_loaded() {
+    use proptest::strategy::ValueTree;
+    let mut runner = proptest::test_runner::TestRunner::deterministic();
+    let mut totals = [
+        (std::time::Duration::ZERO, 0.0),
+        (std::time::Duration::ZERO, 0.0),
+    ];
+    let mut preloaded_totals = [0.0, 0.0];
+    for _ in 0..20 {
+        let rider_count = (1..200u32).new_tree(&mut runner).unwrap().current();
+        let order_count = (0..20_000u64).new_tree(&mut runner).unwrap().current();
+        let riders: Vec<Rider> = (0..rider_count)
+            .map(|id| Rider {
+                id,
+                ..Default::default()
+            })
+            .collect();
+        let orders: Vec<Order> = (0..order_count)
+            .map(|id| Order {
+                id,
+                ..Default::default()
+            })
+            .collect();
+        for (idx, strategy) in [Strategy::RoundRobin, Strategy::LeastLoaded]
+            .into_iter()
+            .enumerate()
+        {
+            let start = std::time::Instant::now();
+            let (plan, _) = compute_plan_with(strategy, &riders, &orders);
+            totals[idx].0 += start.elapsed();
+            totals[idx].1 += assignment_quality(&plan);
+        }
+
+        let busy: Plan = riders
+            .iter()
+            .map(|r| {
+                let load = if r.id % 4 == 0 { 50 } else { 0 };
+                (r.id, (0..load).map(|n| u64::MAX - n).collect())
+            })
+            .collect();
+        let mut round_robin = busy.clone();
+        for (rider_id, new) in compute_plan(&riders, &orders) {
+            round_robin.get_mut(&rider_id).unwrap().extend(new);
+        }
+        let mut least_loaded = busy;
+        add_orders(&mut least_loaded, &orders);
+        preloaded_totals[0] += assignment_quality(&round_robin);
+        preloaded_totals[1] += assignment_quality(&least_loaded);
+    }
+    for (idx, name) in ["round-robin", "least-loaded"].into_iter().enumerate() {
+        println!(
+            "{name}: {:?}, mean stddev {:.3} from empty, {:.3} preloaded",
+            totals[idx].0,
+            totals[idx].1 / 20.0,
+            preloaded_totals[idx] / 20.0
+        );
     }
+    assert_eq!(totals[0].1, totals[1].1);
+    assert!(preloaded_totals[1] <= preloaded_totals[0]);
 }
 
-fn main() {}
+// replay against replay_fast on a long log that is mostly cancels of
+// orders that are already gone.
+// `cargo test --release -- --ignored --nocapture replay_fast_skips_no_ops`
+#[test]
+#[ignore]
+fn replay_fast_skips_no_ops() {
+    let start: Plan = (0..1_000u32)
+        .map(|rider_id| {
+            let first = rider_id as u64 * 10;
+            (rider_id, (first..first + 10).collect())
+        })
+        .collect();
+    let events: Vec<Event> = (0..100_000u64)
+        .map(|n| match n % 10 {
+            0 => Event::OrderAdded {
+                order_id: 1_000_000 + n,
+            },
+            _ => Event::OrderCanceled {
+                order_id: 2_000_000 + n,
+            },
+        })
+        .collect();
+
+    let started = std::time::Instant::now();
+    let slow = replay(start.clone(), &events, &mut MoveClock::default());
+    let slow_time = started.elapsed();
+    let started = std::time::Instant::now();
+    let fast = replay_fast(start, &events, &mut MoveClock::default());
+    let fast_time = started.elapsed();
+    println!("replay {slow_time:?}, replay_fast {fast_time:?}");
+    assert_eq!(fast, slow);
+}
+
+fn main() {
+    #[cfg(feature = "cli")]
+    std::process::exit(cli::run(std::env::args().skip(1).collect()));
+}
@@ -0,0 +1,217 @@
+// Riders, orders and the plan made from them in one JSON document, for
+// attaching a reproduction to a bug report:
+//
+//   {"riders": [{"id": 1, "capacity": null, "location": [52.2, 21.0], ...}],
+//    "orders": [{"id": 10, "weight": 1, ...}],
+//    "plan": {"1": [10]}}
+//
+// Every field is written out, so a scenario reads back exactly. Coordinates
+// that aren't finite can't be expressed in JSON and come back as None.
+
+use crate::json::{Json, JsonError};
+use crate::{plan_from_json, plan_to_json, Order, Plan, Rider};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScenarioError {
+    Json(JsonError),
+    // A field that is missing, holds the wrong kind of value or is out of range
+    BadField(&'static str),
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScenarioError::Json(e) => write!(f, "malformed JSON: {e}"),
+            ScenarioError::BadField(name) => write!(f, "missing or invalid field {name:?}"),
+        }
+    }
+}
+
+impl From<JsonError> for ScenarioError {
+    fn from(e: JsonError) -> Self {
+        ScenarioError::Json(e)
+    }
+}
+
+pub fn export_scenario(riders: &[Rider], orders: &[Order], plan: &Plan) -> String {
+    let object = |fields: Vec<(&str, Json)>| {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    };
+    let riders = riders
+        .iter()
+        .map(|r| {
+            object(vec![
+                ("id", r.id.into()),
+                ("capacity", optional(r.capacity.map(Json::from))),
+                ("location", location(r.location)),
+                ("skill", u64::from(r.skill).into()),
+                ("zone", u64::from(r.zone).into()),
+                ("tags", r.tags.into()),
+                ("shift_start", r.shift_start.into()),
+                ("shift_end", r.shift_end.into()),
+                ("rating", r.rating.into()),
+                ("max_radius_km", optional(r.max_radius_km.map(Json::from))),
+            ])
+        })
+        .collect();
+    let orders = orders
+        .iter()
+        .map(|o| {
+            object(vec![
+                ("id", o.id.into()),
+                ("weight", o.weight.into()),
+                ("address_id", optional(o.address_id.map(Json::from))),
+                ("location", location(o.location)),
+                ("deadline", optional(o.deadline.map(Json::from))),
+                ("complexity", u64::from(o.complexity).into()),
+                ("zone", u64::from(o.zone).into()),
+                ("required_tags", o.required_tags.into()),
+                ("priority", u64::from(o.priority).into()),
+                ("created_at", o.created_at.into()),
+                ("after", optional(o.after.map(Json::from))),
+                ("service_minutes", o.service_minutes.into()),
+                ("value", o.value.into()),
+            ])
+        })
+        .collect();
+    object(vec![
+        ("riders", Json::Array(riders)),
+        ("orders", Json::Array(orders)),
+        ("plan", plan_to_json(plan)),
+    ])
+    .to_string()
+}
+
+pub fn import_scenario(s: &str) -> Result<(Vec<Rider>, Vec<Order>, Plan), ScenarioError> {
+    let json = Json::parse(s)?;
+    let riders = list(&json, "riders")?
+        .iter()
+        .map(|r| {
+            Ok(Rider {
+                id: int(r, "id")?,
+                capacity: optional_int(r, "capacity")?,
+                location: read_location(r)?,
+                skill: int(r, "skill")?,
+                zone: int(r, "zone")?,
+                tags: int(r, "tags")?,
+                shift_start: int(r, "shift_start")?,
+                shift_end: int(r, "shift_end")?,
+                rating: r
+                    .get("rating")
+                    .and_then(Json::as_f64)
+                    .ok_or(ScenarioError::BadField("rating"))?,
+                max_radius_km: match r.get("max_radius_km") {
+                    Some(Json::Null) => None,
+                    radius => Some(
+                        radius
+                            .and_then(Json::as_f64)
+                            .ok_or(ScenarioError::BadField("max_radius_km"))?,
+                    ),
+                },
+            })
+        })
+        .collect::<Result<Vec<_>, ScenarioError>>()?;
+    let orders = list(&json, "orders")?
+        .iter()
+        .map(|o| {
+            Ok(Order {
+                id: int(o, "id")?,
+                weight: int(o, "weight")?,
+                address_id: optional_int(o, "address_id")?,
+                location: read_location(o)?,
+                deadline: optional_int(o, "deadline")?,
+                complexity: int(o, "complexity")?,
+                zone: int(o, "zone")?,
+                required_tags: int(o, "required_tags")?,
+                priority: int(o, "priority")?,
+                created_at: int(o, "created_at")?,
+                after: optional_int(o, "after")?,
+                service_minutes: int(o, "service_minutes")?,
+                value: int(o, "value")?,
+            })
+        })
+        .collect::<Result<Vec<_>, ScenarioError>>()?;
+    let plan = json
+        .get("plan")
+        .ok_or(ScenarioError::BadField("plan"))
+        .and_then(|plan| plan_from_json(plan).ok_or(ScenarioError::BadField("plan")))?;
+    Ok((riders, orders, plan))
+}
+
+fn optional(value: Option<Json>) -> Json {
+    value.unwrap_or(Json::Null)
+}
+
+fn location(location: Option<(f64, f64)>) -> Json {
+    match location {
+        Some((lat, lon)) if lat.is_finite() && lon.is_finite() => {
+            Json::Array(vec![lat.into(), lon.into()])
+        }
+        _ => Json::Null,
+    }
+}
+
+fn list<'a>(json: &'a Json, name: &'static str) -> Result<&'a [Json], ScenarioError> {
+    json.get(name)
+        .and_then(Json::as_array)
+        .ok_or(ScenarioError::BadField(name))
+}
+
+fn int<T: TryFrom<u64>>(json: &Json, name: &'static str) -> Result<T, ScenarioError> {
+    json.get(name)
+        .and_then(Json::as_u64)
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or(ScenarioError::BadField(name))
+}
+
+fn optional_int<T: TryFrom<u64>>(
+    json: &Json,
+    name: &'static str,
+) -> Result<Option<T>, ScenarioError> {
+    match json.get(name) {
+        Some(Json::Null) => Ok(None),
+        _ => int(json, name).map(Some),
+    }
+}
+
+fn read_location(json: &Json) -> Result<Option<(f64, f64)>, ScenarioError> {
+    let bad = ScenarioError::BadField("location");
+    match json.get("location").ok_or(bad.clone())? {
+        Json::Null => Ok(None),
+        location => match location.as_array() {
+            Some([lat, lon]) => Ok(Some((
+                lat.as_f64().ok_or(bad.clone())?,
+                lon.as_f64().ok_or(bad)?,
+            ))),
+            _ => Err(bad),
+        },
+    }
+}
+
+#[test]
+fn broken_scenarios_name_the_bad_field() {
+    let scenario = export_scenario(&[], &[], &Plan::default());
+    assert_eq!(scenario, r#"{"riders":[],"orders":[],"plan":{}}"#);
+    assert_eq!(
+        import_scenario(r#"{"riders":[],"orders":[]}"#).unwrap_err(),
+        ScenarioError::BadField("plan")
+    );
+    assert_eq!(
+        import_scenario(r#"{"riders":[{"id":1}],"orders":[],"plan":{}}"#).unwrap_err(),
+        ScenarioError::BadField("capacity")
+    );
+    assert_eq!(
+        import_scenario(r#"{"riders":[],"orders":[{"id":1,"weight":-1}],"plan":{}}"#).unwrap_err(),
+        ScenarioError::BadField("weight")
+    );
+    assert!(matches!(
+        import_scenario("{"),
+        Err(ScenarioError::Json(JsonError::UnexpectedEnd))
+    ));
+}
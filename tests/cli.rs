@@ -0,0 +1,38 @@
+// Runs the command line end to end: `cargo test --features cli`
+#![cfg(feature = "cli")]
+
+use std::path::Path;
+use std::process::Command;
+
+fn prop(state: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_prop"))
+        .arg("--state")
+        .arg(state)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn reject_moves_the_order_in_the_saved_plan() {
+    let state = std::env::temp_dir().join(format!("prop-cli-{}.json", std::process::id()));
+
+    let computed = prop(&state, &["compute", "--riders", "2", "--orders", "3"]);
+    assert_eq!(computed, "rider 1: 1 3\nrider 2: 2\n");
+    let rejected = prop(&state, &["event", "reject", "--rider", "1", "--order", "1"]);
+    assert_eq!(rejected, "rider 1: 3\nrider 2: 2 1\n");
+    assert_eq!(prop(&state, &["show"]), rejected);
+
+    std::fs::remove_file(&state).unwrap();
+}
+
+#[test]
+fn bad_usage_exits_with_2() {
+    let status = Command::new(env!("CARGO_BIN_EXE_prop"))
+        .args(["compute", "--riders", "two"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+}